@@ -1,3 +1,5 @@
+use adb_sync::discovery::Candidate;
+
 pub fn main() -> anyhow::Result<()> {
     let interfaces = pnet_datalink::interfaces()
         .into_iter()
@@ -5,11 +7,12 @@ pub fn main() -> anyhow::Result<()> {
         .collect::<Vec<_>>();
     for x in interfaces {
         for ip in x.ips {
-            if ip.is_ipv4() {
-                let mut ip_str = format!("{}", ip);
-                ip_str.truncate(ip_str.find('/').unwrap());
-                println!("{}", ip_str);
-            }
+            let candidate = Candidate {
+                ip: ip.ip(),
+                prefix: ip.prefix(),
+                interface: x.name.clone(),
+            };
+            println!("{}", candidate);
         }
     }
     Ok(())
@@ -1,26 +1,49 @@
-use adb_sync::{any_ipv4_socket, IP_CHECKER_PORT};
+use adb_sync::discovery::bind_dual_stack;
+use adb_sync::report_listening;
+use clap::Parser;
 use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
+use std::net::TcpStream;
 use std::process;
 use std::thread::{sleep, spawn};
 use std::time::Duration;
 
+#[derive(Parser)]
+struct Args {
+    /// Give up if no host probe arrives in time. The host normally ends this earlier
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    timeout: Duration,
+    /// Port to listen on. 0 picks an ephemeral one
+    #[arg(long, default_value = "0")]
+    port: u16,
+}
+
 pub fn main() -> anyhow::Result<()> {
-    spawn(|| {
-        sleep(Duration::from_secs(2));
+    let args = Args::parse();
+    spawn(move || {
+        sleep(args.timeout);
         process::exit(0);
     });
 
-    // a simple echo server, to test the connectivity
-    let socket_addr = any_ipv4_socket(IP_CHECKER_PORT);
-    let listener = TcpListener::bind(socket_addr).unwrap();
-    println!("Listening on {}", socket_addr);
-    let (mut stream, addr) = listener.accept()?;
-    println!("Connected from: {}", addr);
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    let mut line = String::new();
-    reader.read_line(&mut line).unwrap();
-    drop(reader);
-    writeln!(&mut stream, "{}", line)?;
-    Ok(())
+    // an echo server, to test the connectivity of every address the host probes
+    let listener = bind_dual_stack(args.port)?;
+    report_listening(listener.local_addr()?);
+    loop {
+        let (stream, addr) = listener.accept()?;
+        println!("Connected from: {}", addr);
+        spawn(move || {
+            let _ = echo(stream);
+        });
+    }
+}
+
+fn echo(mut stream: TcpStream) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        stream.write_all(&line)?;
+    }
 }
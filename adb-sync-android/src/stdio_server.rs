@@ -5,6 +5,6 @@ use std::io::{stdin, stdout};
 
 pub fn main() -> anyhow::Result<()> {
     let stream = ReadWriteFlush(ReadWrite::new(stdin(), stdout()));
-    handle_connection(stream)?;
+    handle_connection(stream, None)?;
     Ok(())
 }
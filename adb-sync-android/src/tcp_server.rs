@@ -1,17 +1,88 @@
-use std::net::{SocketAddr, TcpListener};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
 
-use adb_sync::stream::android::handle_connection;
-use adb_sync::ADB_SYNC_PORT;
+use adb_sync::discovery::ScopedIp;
+use adb_sync::report_listening;
+use adb_sync::stream::android::{handshake, serve_connection};
+use adb_sync::stream::auth::AuthToken;
+use adb_sync::stream::crypto::{EncryptedStream, Role};
+use clap::Parser;
+
+/// How long a peer may stall before it has authenticated
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+struct Args {
+    /// Hex-encoded session token the host has to prove knowledge of
+    #[arg(long)]
+    token: String,
+    /// Wrap the connection in an encrypted channel keyed by the token
+    #[arg(long)]
+    encrypt: bool,
+    /// Only listen on this address. A link-local one may omit the zone
+    #[arg(long)]
+    bind: ScopedIp,
+    /// Port to listen on. 0 picks an ephemeral one
+    #[arg(long, default_value = "0")]
+    port: u16,
+}
 
 pub fn main() -> anyhow::Result<()> {
-    let listener = TcpListener::bind(
-        format!("0.0.0.0:{}", ADB_SYNC_PORT)
-            .parse::<SocketAddr>()
-            .unwrap(),
-    )?;
-    let (mut stream, from) = listener.accept()?;
-    println!("Connected from: {}", from);
+    let args = Args::parse();
+    let token = AuthToken::from_hex(&args.token)?;
+
+    let listener = TcpListener::bind(args.bind.socket_addr(args.port)?)?;
+    report_listening(listener.local_addr()?);
+    loop {
+        let (mut stream, from) = match listener.accept() {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Failed to accept: {}", e);
+                continue;
+            }
+        };
+        println!("Connected from: {}", from);
+
+        // shares the socket, to lift the timeout once the stream is wrapped
+        let socket = stream.try_clone()?;
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let served = if args.encrypt {
+            match EncryptedStream::new(&mut stream, &token, Role::Android) {
+                Ok(stream) => session(stream, &token, &socket, from)?,
+                Err(e) => {
+                    eprintln!("Rejected {}: {}", from, e);
+                    false
+                }
+            }
+        } else {
+            session(&mut stream, &token, &socket, from)?
+        };
+        if served {
+            return Ok(());
+        }
+    }
+}
 
-    handle_connection(&mut stream)?;
-    Ok(())
+/// Serve `stream` if the peer authenticates. Returns `false` if it's rejected instead.
+///
+/// Anything failing before the challenge succeeds, like a wrong `MAGIC`, an early EOF or
+/// an undecodable `Hello`, is blamed on the peer not being our host.
+fn session<S: Read + Write>(
+    mut stream: S,
+    token: &AuthToken,
+    socket: &TcpStream,
+    from: SocketAddr,
+) -> anyhow::Result<bool> {
+    let capabilities = match handshake(&mut stream, Some(token)) {
+        Ok(capabilities) => capabilities,
+        Err(e) => {
+            // not our host; keep waiting for the real one
+            eprintln!("Rejected {}: {}", from, e);
+            return Ok(false);
+        }
+    };
+    socket.set_read_timeout(None)?;
+    serve_connection(stream, capabilities)?;
+    Ok(true)
 }
@@ -0,0 +1,259 @@
+//! Android IP discovery
+//!
+//! `get-ip` prints one [`Candidate`] per line, `ip-checker` echoes lines back on every
+//! connection, and the host probes all the candidates concurrently to pick the best link.
+
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::debug;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const PROBE_ROUNDS: usize = 3;
+const ECHO_MESSAGE: &[u8] = b"Please echo\n";
+
+/// Interface name prefixes Android uses for USB tethering
+const USB_INTERFACE_PREFIXES: [&str; 3] = ["rndis", "usb", "ncm"];
+
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => false,
+        IpAddr::V6(v6) => v6.is_unicast_link_local(),
+    }
+}
+
+fn interface_index(name: &str) -> Option<u32> {
+    pnet_datalink::interfaces()
+        .into_iter()
+        .find(|x| x.name == name)
+        .map(|x| x.index)
+}
+
+/// Index of the local interface that owns `ip`
+fn owner_interface_index(ip: IpAddr) -> Option<u32> {
+    pnet_datalink::interfaces()
+        .into_iter()
+        .find(|x| x.ips.iter().any(|n| n.ip() == ip))
+        .map(|x| x.index)
+}
+
+/// An IP address with an optional zone, like `fe80::1%wlan0`
+///
+/// The zone is an interface name or index of the machine that parses it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopedIp {
+    pub ip: IpAddr,
+    pub zone: Option<String>,
+}
+
+impl Display for ScopedIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.zone {
+            None => write!(f, "{}", self.ip),
+            Some(zone) => write!(f, "{}%{}", self.ip, zone),
+        }
+    }
+}
+
+impl FromStr for ScopedIp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, zone) = match s.split_once('%') {
+            None => (s, None),
+            Some((ip, zone)) => (ip, Some(zone.into())),
+        };
+        Ok(Self {
+            ip: ip.parse()?,
+            zone,
+        })
+    }
+}
+
+impl ScopedIp {
+    /// Resolve the zone against the local interfaces.
+    ///
+    /// A link-local address without a zone is looked up among the local addresses,
+    /// so the Android side can bind one without the host knowing its interface names.
+    pub fn socket_addr(&self, port: u16) -> anyhow::Result<SocketAddr> {
+        let IpAddr::V6(v6) = self.ip else {
+            return Ok(SocketAddr::new(self.ip, port));
+        };
+        let scope_id = match &self.zone {
+            Some(zone) => match zone.parse::<u32>() {
+                Ok(index) => index,
+                Err(_) => {
+                    interface_index(zone).ok_or_else(|| anyhow!("Unknown interface: {}", zone))?
+                }
+            },
+            None if v6.is_unicast_link_local() => owner_interface_index(self.ip)
+                .ok_or_else(|| anyhow!("Link-local address needs a zone: {}", self.ip))?,
+            None => 0,
+        };
+        Ok(SocketAddrV6::new(v6, port, 0, scope_id).into())
+    }
+}
+
+/// Listen on all the addresses, IPv4 and IPv6
+///
+/// `[::]` is dual-stack on Linux; fall back to IPv4 on devices without IPv6.
+pub fn bind_dual_stack(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
+        .or_else(|_| TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)))
+}
+
+/// An address of an Android network interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub ip: IpAddr,
+    pub prefix: u8,
+    pub interface: String,
+}
+
+impl Display for Candidate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if is_link_local(self.ip) {
+            write!(f, "{}%{}", self.ip, self.interface)?;
+        } else {
+            write!(f, "{}", self.ip)?;
+        }
+        write!(f, "/{} {}", self.prefix, self.interface)
+    }
+}
+
+impl FromStr for Candidate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, interface) = s
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Invalid candidate: {}", s))?;
+        let (ip, prefix) = network
+            .split_once('/')
+            .ok_or_else(|| anyhow!("Invalid candidate: {}", s))?;
+        Ok(Self {
+            // the zone is the Android interface name, meaningless to the host
+            ip: ip.parse::<ScopedIp>()?.ip,
+            prefix: prefix.parse()?,
+            interface: interface.into(),
+        })
+    }
+}
+
+impl Candidate {
+    pub fn is_usb_tethering(&self) -> bool {
+        USB_INTERFACE_PREFIXES
+            .iter()
+            .any(|x| self.interface.starts_with(x))
+    }
+
+    /// Whether `ip` is in the same subnet as this candidate.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(a) & mask == u32::from(b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(a) & mask == u128::from(b) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Probe {
+    pub candidate: Candidate,
+    /// Address the host reached the candidate at, with the host zone for link-local ones
+    pub addr: SocketAddr,
+    /// Best round-trip time of all the rounds
+    pub rtt: Duration,
+    /// The host reached it from an address inside the candidate's subnet
+    pub same_subnet: bool,
+}
+
+fn probe(candidate: Candidate, addr: SocketAddr) -> anyhow::Result<Probe> {
+    let mut stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    let same_subnet = candidate.contains(stream.local_addr()?.ip());
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = Vec::new();
+    let mut rtt = Duration::MAX;
+    for _ in 0..PROBE_ROUNDS {
+        let start = Instant::now();
+        stream.write_all(ECHO_MESSAGE)?;
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        if line != ECHO_MESSAGE {
+            return Err(anyhow!("Not equal"));
+        }
+        rtt = rtt.min(start.elapsed());
+    }
+    Ok(Probe {
+        candidate,
+        addr,
+        rtt,
+        same_subnet,
+    })
+}
+
+/// Probe all the candidates concurrently, and rank the reachable ones, best first.
+///
+/// USB tethering beats a same-subnet link, which beats a routed one; ties are broken by RTT.
+pub fn check_connectivity(candidates: Vec<Candidate>, port: u16) -> Vec<Probe> {
+    // a link-local candidate may be reachable through any of the host interfaces
+    let host_scope_ids = pnet_datalink::interfaces()
+        .into_iter()
+        .filter(|x| x.is_up() && !x.is_loopback())
+        .filter(|x| x.ips.iter().any(|n| is_link_local(n.ip())))
+        .map(|x| x.index)
+        .collect::<Vec<_>>();
+
+    let mut handlers = Vec::new();
+    for candidate in candidates {
+        debug!("check ip: {}", candidate);
+        let addrs = match candidate.ip {
+            IpAddr::V6(v6) if v6.is_unicast_link_local() => host_scope_ids
+                .iter()
+                .map(|&scope_id| SocketAddrV6::new(v6, port, 0, scope_id).into())
+                .collect(),
+            ip => vec![SocketAddr::new(ip, port)],
+        };
+        for addr in addrs {
+            let candidate = candidate.clone();
+            handlers.push(spawn(move || probe(candidate, addr)));
+        }
+    }
+    let mut probes = Vec::new();
+    for x in handlers {
+        match x.join().unwrap() {
+            Ok(probe) => {
+                debug!(
+                    "reachable: {} via {} (rtt: {:?}, same subnet: {})",
+                    probe.candidate, probe.addr, probe.rtt, probe.same_subnet
+                );
+                probes.push(probe);
+            }
+            Err(e) => debug!("unreachable: {}", e),
+        }
+    }
+    probes.sort_by_key(|x| {
+        (
+            Reverse(x.candidate.is_usb_tethering()),
+            Reverse(x.same_subnet),
+            x.rtt,
+        )
+    });
+    probes
+}
@@ -0,0 +1,252 @@
+//! `--include` and `--exclude` rules, and the presets expanding into them
+//!
+//! Rules are globs on the paths relative to the source directory, tried in order; the first
+//! one matching a file decides, and a file no rule matches is kept. A glob with a `/` is
+//! matched against the path and its parent directories, like `Android/data`; one without is
+//! matched against every path component, like `*.jpg` or `.thumbnails`. Matching ignores
+//! case, as the shared storage of Android does.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use bincode::{Decode, Encode};
+use clap::ValueEnum;
+use globset::{GlobBuilder, GlobMatcher};
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FilterRule {
+    Include(String),
+    Exclude(String),
+}
+
+/// Named rule sets for the shared storage root, like `/sdcard/`
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Photos and videos of `DCIM` and `Pictures`
+    Photos,
+    /// Documents of `Documents` and `Download`
+    Documents,
+    /// Leave out app data, thumbnails and trashed or pending MediaStore files
+    NoCache,
+}
+
+/// Files MediaStore keeps around, but which aren't really there
+const MEDIA_STORE_JUNK: &[&str] = &[".thumbnails", ".trashed-*", ".pending-*"];
+
+impl Preset {
+    /// Returns the excludes and the includes.
+    fn rules(self) -> (Vec<&'static str>, Vec<&'static str>) {
+        match self {
+            Preset::Photos => (
+                MEDIA_STORE_JUNK.to_vec(),
+                vec![
+                    "{DCIM,Pictures}/**/*.{jpg,jpeg,png,gif,webp,heic,heif,avif,dng}",
+                    "{DCIM,Pictures}/**/*.{mp4,mov,3gp,mkv,webm}",
+                ],
+            ),
+            Preset::Documents => (
+                MEDIA_STORE_JUNK.to_vec(),
+                vec![
+                    "{Documents,Download}/**/*.{pdf,epub,txt,md,rtf,csv}",
+                    "{Documents,Download}/**/*.{doc,docx,odt,xls,xlsx,ods,ppt,pptx,odp}",
+                ],
+            ),
+            Preset::NoCache => (
+                [&["Android/data", "Android/obb"], MEDIA_STORE_JUNK].concat(),
+                Vec::new(),
+            ),
+        }
+    }
+}
+
+/// Put `presets` after `user_rules`, so the user rules take precedence.
+///
+/// The excludes of all presets come before their includes, and if there are includes,
+/// everything else is excluded at the end.
+pub fn expand_presets(user_rules: Vec<FilterRule>, presets: &[Preset]) -> Vec<FilterRule> {
+    let mut excludes = Vec::new();
+    let mut includes = Vec::new();
+    for preset in presets {
+        let (x, y) = preset.rules();
+        excludes.extend(x.into_iter().map(|x| FilterRule::Exclude(x.into())));
+        includes.extend(y.into_iter().map(|x| FilterRule::Include(x.into())));
+    }
+    if !includes.is_empty() {
+        includes.push(FilterRule::Exclude("*".into()));
+    }
+    // presets share their junk excludes
+    let mut seen = HashSet::new();
+    excludes.retain(|x| seen.insert(x.clone()));
+    [user_rules, excludes, includes].concat()
+}
+
+pub struct CompiledRules(
+    Vec<(
+        bool, /* include */
+        bool, /* has slash */
+        GlobMatcher,
+    )>,
+);
+
+impl CompiledRules {
+    pub fn new(rules: &[FilterRule]) -> anyhow::Result<Self> {
+        let mut compiled = Vec::new();
+        for rule in rules {
+            let (include, glob) = match rule {
+                FilterRule::Include(x) => (true, x),
+                FilterRule::Exclude(x) => (false, x),
+            };
+            let glob = glob.trim_end_matches('/');
+            let matcher = GlobBuilder::new(glob)
+                .literal_separator(true)
+                .case_insensitive(true)
+                .build()?
+                .compile_matcher();
+            compiled.push((include, glob.contains('/'), matcher));
+        }
+        Ok(Self(compiled))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn keeps(&self, path: &Path) -> bool {
+        self.first_match(path).is_none_or(|(_, include)| include)
+    }
+
+    /// Whether nothing under the directory `path` can be kept, as it's excluded before any
+    /// include rule
+    pub fn prunes(&self, path: &Path) -> bool {
+        let first_include = self.0.iter().position(|(include, ..)| *include);
+        match self.first_match(path) {
+            Some((index, false)) => first_include.is_none_or(|x| index < x),
+            _ => false,
+        }
+    }
+
+    /// The index and the kind of the first rule matching `path`
+    fn first_match(&self, path: &Path) -> Option<(usize, bool)> {
+        self.0
+            .iter()
+            .enumerate()
+            .find(|(_, (_, has_slash, matcher))| {
+                if *has_slash {
+                    path.ancestors()
+                        .filter(|x| !x.as_os_str().is_empty())
+                        .any(|x| matcher.is_match(x))
+                } else {
+                    path.iter().any(|x| matcher.is_match(x))
+                }
+            })
+            .map(|(index, (include, ..))| (index, *include))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(rules: Vec<FilterRule>) -> CompiledRules {
+        CompiledRules::new(&rules).unwrap()
+    }
+
+    fn include(glob: &str) -> FilterRule {
+        FilterRule::Include(glob.into())
+    }
+
+    fn exclude(glob: &str) -> FilterRule {
+        FilterRule::Exclude(glob.into())
+    }
+
+    fn keeps(rules: &CompiledRules, path: &str) -> bool {
+        rules.keeps(Path::new(path))
+    }
+
+    #[test]
+    fn no_rule_keeps_everything() {
+        let rules = compile(Vec::new());
+        assert!(rules.is_empty());
+        assert!(keeps(&rules, "a/b.txt"));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = compile(vec![include("*.jpg"), exclude("*.jpg"), exclude("*")]);
+        assert!(keeps(&rules, "DCIM/a.jpg"));
+        assert!(!keeps(&rules, "DCIM/a.txt"));
+    }
+
+    #[test]
+    fn globs_without_slash_match_any_component() {
+        let rules = compile(vec![exclude(".thumbnails"), exclude("*.tmp")]);
+        assert!(!keeps(&rules, "DCIM/.thumbnails/1.jpg"));
+        assert!(!keeps(&rules, "a/b.tmp"));
+        assert!(keeps(&rules, "a/tmp"));
+    }
+
+    #[test]
+    fn globs_with_slash_match_from_the_root() {
+        let rules = compile(vec![exclude("Android/data/")]);
+        assert!(!keeps(&rules, "Android/data"));
+        assert!(!keeps(&rules, "Android/data/com.app/f"));
+        assert!(keeps(&rules, "Android/media/f"));
+        assert!(keeps(&rules, "backup/Android/data/f"));
+    }
+
+    #[test]
+    fn matching_ignores_case() {
+        let rules = compile(vec![include("dcim/**/*.jpg"), exclude("*")]);
+        assert!(keeps(&rules, "DCIM/Camera/IMG_1.JPG"));
+    }
+
+    #[test]
+    fn star_stays_within_a_component() {
+        let rules = compile(vec![exclude("DCIM/*.jpg")]);
+        assert!(!keeps(&rules, "DCIM/a.jpg"));
+        assert!(keeps(&rules, "DCIM/Camera/a.jpg"));
+    }
+
+    #[test]
+    fn prunes_only_before_includes() {
+        let rules = compile(vec![exclude("Android"), include("*.jpg"), exclude("*")]);
+        assert!(rules.prunes(Path::new("Android")));
+        assert!(!rules.prunes(Path::new("DCIM")));
+        let rules = compile(vec![include("*.jpg"), exclude("Android")]);
+        assert!(!rules.prunes(Path::new("Android")));
+    }
+
+    #[test]
+    fn photos_preset() {
+        let rules = compile(expand_presets(Vec::new(), &[Preset::Photos]));
+        assert!(keeps(&rules, "DCIM/Camera/IMG_1.jpg"));
+        assert!(keeps(&rules, "Pictures/Screenshots/a.png"));
+        assert!(!keeps(&rules, "DCIM/Camera/.trashed-123-IMG_2.jpg"));
+        assert!(!keeps(&rules, "Music/a.mp3"));
+        assert!(!rules.prunes(Path::new("DCIM")));
+        assert!(rules.prunes(Path::new("DCIM/.thumbnails")));
+    }
+
+    #[test]
+    fn user_rules_come_before_presets() {
+        let rules = compile(expand_presets(
+            vec![include("Music/**")],
+            &[Preset::Photos, Preset::Documents],
+        ));
+        assert!(keeps(&rules, "Music/a.mp3"));
+        assert!(keeps(&rules, "Download/a.pdf"));
+        assert!(!keeps(&rules, "Download/a.apk"));
+    }
+
+    #[test]
+    fn presets_share_excludes() {
+        let rules = expand_presets(Vec::new(), &[Preset::Photos, Preset::NoCache]);
+        let excludes = rules
+            .iter()
+            .filter(|x| **x == exclude(".thumbnails"))
+            .count();
+        assert_eq!(excludes, 1);
+        let rules = compile(rules);
+        assert!(!keeps(&rules, "Android/obb/x"));
+    }
+}
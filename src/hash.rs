@@ -0,0 +1,166 @@
+//! Content digests of the send stream records
+//!
+//! Chunks are always guarded by CRC-32; the record digest is chosen by the host among the
+//! algorithms both sides support.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use ::crc as crc_lib;
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::stream::protocol::Capabilities;
+
+static CRC: crc_lib::Crc<u32> = crc_lib::Crc::<u32>::new(&crc_lib::CRC_32_CKSUM);
+
+#[derive(
+    Encode,
+    Decode,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// CRC-32/CKSUM; only guards against line noise
+    Crc32,
+    #[default]
+    Blake3,
+    /// XXH3-128; fast, but not cryptographic
+    Xxh3,
+    Sha256,
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            HashAlgorithm::Crc32 => "crc32",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Sha256 => "sha256",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl HashAlgorithm {
+    /// The capability a peer needs for this algorithm
+    pub fn capability(self) -> Capabilities {
+        match self {
+            HashAlgorithm::Crc32 => Capabilities::NONE,
+            HashAlgorithm::Blake3 => Capabilities::BLAKE3,
+            HashAlgorithm::Xxh3 => Capabilities::XXH3,
+            HashAlgorithm::Sha256 => Capabilities::SHA256,
+        }
+    }
+
+    pub fn digest_length(self) -> usize {
+        match self {
+            HashAlgorithm::Crc32 => 4,
+            HashAlgorithm::Blake3 => blake3::OUT_LEN,
+            HashAlgorithm::Xxh3 => 16,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// Name of the checksum file the matching `*sum` tool can check
+    pub fn sums_file_name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc32 => "CRC32SUMS",
+            HashAlgorithm::Blake3 => "B3SUMS",
+            HashAlgorithm::Xxh3 => "XXH128SUMS",
+            HashAlgorithm::Sha256 => "SHA256SUMS",
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Crc32 => Hasher::Crc32(CRC.digest()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Box::new(Sha256::new())),
+        }
+    }
+}
+
+pub enum Hasher {
+    Crc32(crc_lib::Digest<'static, u32>),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+    Sha256(Box<Sha256>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(x) => x.update(data),
+            Hasher::Blake3(x) => {
+                x.update(data);
+            }
+            Hasher::Xxh3(x) => x.update(data),
+            Hasher::Sha256(x) => x.update(data),
+        }
+    }
+
+    /// The digest, in the byte order the usual `*sum` tools print it
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Crc32(x) => x.finalize().to_be_bytes().to_vec(),
+            Hasher::Blake3(x) => x.finalize().as_bytes().to_vec(),
+            Hasher::Xxh3(x) => x.digest128().to_be_bytes().to_vec(),
+            Hasher::Sha256(x) => x.finalize().to_vec(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hash the whole content of a file.
+pub fn hash_file<P: AsRef<Path>>(path: P, algorithm: HashAlgorithm) -> io::Result<Vec<u8>> {
+    let mut hasher = algorithm.hasher();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Hash the bytes from `start` to `end` of a file, which must have them all.
+pub fn hash_file_range<P: AsRef<Path>>(
+    path: P,
+    algorithm: HashAlgorithm,
+    start: u64,
+    end: u64,
+) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut hasher = algorithm.hasher();
+    let length = end.saturating_sub(start);
+    if io::copy(&mut file.take(length), &mut hasher)? != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "File is shorter than the range",
+        ));
+    }
+    Ok(hasher.finalize())
+}
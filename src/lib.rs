@@ -1,6 +1,8 @@
 #![feature(try_blocks)]
 #![feature(yeet_expr)]
 
+use crate::filter::{CompiledRules, FilterRule};
+use crate::perms::Chmod;
 use crate::stream::protocol::SendConfig;
 use crate::unix_path::UnixPath;
 use bincode::config::Configuration;
@@ -8,23 +10,40 @@ use bincode::{Decode, Encode};
 use colored::Colorize;
 use fern::colors::{Color, ColoredLevelConfig};
 use once_cell::sync::Lazy;
+use std::collections::HashSet;
 use std::env::{args, current_exe};
-use std::io::Read;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, Read};
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, spawn};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{env, io};
 
 pub mod crc;
+pub mod discovery;
+pub mod filter;
+pub mod hash;
+mod manifest;
+pub mod perms;
+mod renames;
 mod send_stream;
 pub mod stream;
 pub mod unix_path;
+pub mod xattrs;
 
-pub const ADB_SYNC_PORT: u16 = 5001;
-pub const IP_CHECKER_PORT: u16 = 5002;
-pub static ANY_IPV4_ADDR: Lazy<Ipv4Addr> = Lazy::new(|| "0.0.0.0".parse().unwrap());
+/// Android servers print this followed by their bound socket address once they
+/// accept connections. The host waits for it instead of guessing with sleeps.
+pub const LISTENING_PREFIX: &str = "adb-sync: listening on ";
 
 pub static CONFIG: Lazy<Mutex<Option<Config>>> = Lazy::new(|| Mutex::new(None));
 
@@ -33,6 +52,48 @@ pub struct Config {
     pub send_config: SendConfig,
     pub dest_path: PathBuf,
     pub ignore_mtime: bool,
+    /// Append the digests of received files here
+    pub digest_log: Option<PathBuf>,
+    /// Keep a manifest of the received files in the destination
+    pub manifest: bool,
+    /// Describes the Android device in the manifest
+    pub source_device: String,
+    pub mode: Mode,
+    pub perms: bool,
+    /// Only takes effect as root
+    pub owner: bool,
+    pub chmod: Option<Chmod>,
+    /// Skip files newer on the host
+    pub update: bool,
+    /// Skip files already on the host
+    pub ignore_existing: bool,
+    /// Skip files not on the host yet
+    pub existing: bool,
+    /// Report the decision on every file
+    pub verbose: bool,
+    /// Only report what would be sent
+    pub dry_run: bool,
+    /// Modification times this close count as equal; `None` detects it on the destination
+    pub modify_window: Option<Duration>,
+    /// Move host files to where Android moved them, instead of transferring them again
+    pub detect_renames: bool,
+    /// Confirm detected renames by their digests
+    pub verify_renames: bool,
+    /// Only send what's past the end of a shorter host copy
+    pub append: bool,
+    /// Compare the end of a shorter host copy with Android before appending to it
+    pub append_verify: bool,
+    /// Print a summary in JSON to stdout, in place of the received paths
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Sync,
+    /// Compare the host copy with Android; with `repair`, send what doesn't match.
+    Verify {
+        repair: bool,
+    },
 }
 
 macro_rules! count {
@@ -54,15 +115,71 @@ macro_rules! const_android_call_name {
 
 const_android_call_name!("ip-checker", "tcp-server", "stdio-server", "get-ip");
 
-pub fn any_ipv4_socket(port: u16) -> SocketAddr {
-    (*ANY_IPV4_ADDR, port).into()
+/// Error context naming the file an error is about
+///
+/// Android looks it up to fill the path of `Message::Error`.
+#[derive(Debug, Clone)]
+pub struct PathContext(pub PathBuf);
+
+impl Display for PathContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
 pub struct Entry {
     pub path: UnixPath,
     pub size: u64,
     pub modified: SystemTime,
+    /// `st_mode`, with the file type bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Identify the hard links of a file, together with `nlink`
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+}
+
+/// A file Android left out, while indexing or while sending
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub path: UnixPath,
+    pub reason: String,
+}
+
+#[derive(Encode, Decode, Debug, Default)]
+pub struct Index {
+    pub entries: Vec<Entry>,
+    /// Files that couldn't be indexed
+    pub skipped: Vec<Skipped>,
+    /// How many files [`IndexFilter`] left out
+    pub filtered: usize,
+    /// How many directories the rules left out whole, without reading them
+    pub pruned_dirs: usize,
+}
+
+/// Size and age limits of the indexed files, and which paths to keep
+#[derive(Encode, Decode, Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexFilter {
+    /// `--include`, `--exclude` and the presets, in order
+    pub rules: Vec<FilterRule>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Only files modified at or after this
+    pub newer_than: Option<SystemTime>,
+    /// Only files modified before this
+    pub older_than: Option<SystemTime>,
+}
+
+impl IndexFilter {
+    pub fn keeps(&self, entry: &Entry) -> bool {
+        self.min_size.is_none_or(|x| entry.size >= x)
+            && self.max_size.is_none_or(|x| entry.size <= x)
+            && self.newer_than.is_none_or(|x| entry.modified >= x)
+            && self.older_than.is_none_or(|x| entry.modified < x)
+    }
 }
 
 pub fn cli_args() -> Vec<String> {
@@ -119,22 +236,66 @@ where
     }
 }
 
-pub fn index_dir<P: AsRef<Path>>(dir: P, skip_failed: bool) -> io::Result<Vec<Entry>> {
-    let walk_dir = jwalk::WalkDir::new(dir.as_ref()).skip_hidden(false);
-    let mut entries = Vec::new();
+pub fn index_dir<P: AsRef<Path>>(
+    dir: P,
+    skip_failed: bool,
+    filter: &IndexFilter,
+) -> anyhow::Result<Index> {
+    let rules = Arc::new(CompiledRules::new(&filter.rules)?);
+    let pruned = Arc::new(AtomicUsize::new(0));
+    let mut walk_dir = jwalk::WalkDir::new(dir.as_ref()).skip_hidden(false);
+    if !rules.is_empty() {
+        let (rules, pruned) = (Arc::clone(&rules), Arc::clone(&pruned));
+        let root = dir.as_ref().to_path_buf();
+        walk_dir = walk_dir.process_read_dir(move |_, _, _, children| {
+            for x in children.iter_mut().flatten() {
+                if x.file_type.is_dir()
+                    && let Ok(path) = x.path().strip_prefix(&root)
+                    && rules.prunes(path)
+                {
+                    // nothing to keep under it; don't even read it
+                    x.read_children_path = None;
+                    pruned.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+    let relative = |path: &Path| -> UnixPath {
+        pathdiff::diff_paths(path, dir.as_ref())
+            .unwrap_or_else(|| path.into())
+            .into()
+    };
+    let mut index = Index::default();
     for x in walk_dir {
-        let Ok(entry) = x else {
-            if skip_failed {
-                eprintln!("Failed to index: {:?}", x);
+        let entry = match x {
+            Ok(entry) => entry,
+            Err(e) if skip_failed => {
+                index.skipped.push(Skipped {
+                    path: relative(e.path().unwrap_or(dir.as_ref())),
+                    reason: e.to_string(),
+                });
                 continue;
-            } else {
-                return Err(io::Error::from(x.err().unwrap()));
+            }
+            Err(e) => {
+                let path = e.path().unwrap_or(dir.as_ref()).to_path_buf();
+                return Err(anyhow::Error::from(io::Error::from(e)).context(PathContext(path)));
             }
         };
         if entry.file_type.is_dir() {
             // don't send directories
             continue;
         }
+        if !entry.file_type.is_file() {
+            // symlinks, pipes etc. aren't supported, but the host should know they're left out
+            let path = relative(&entry.path());
+            if rules.keeps(&path.0) {
+                index.skipped.push(Skipped {
+                    path,
+                    reason: "Not a regular file".into(),
+                });
+            }
+            continue;
+        }
         let result: io::Result<Entry> = try {
             let metadata = entry.metadata()?;
             let path = entry.path();
@@ -143,51 +304,175 @@ pub fn index_dir<P: AsRef<Path>>(dir: P, skip_failed: bool) -> io::Result<Vec<En
                 path: relative_path.into(),
                 size: metadata.len(),
                 modified: metadata.modified()?,
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+                nlink: metadata.nlink(),
             }
         };
         match result {
+            Ok(e) if !filter.keeps(&e) || !rules.keeps(&e.path.0) => {
+                index.filtered += 1;
+            }
             Ok(e) => {
-                entries.push(e);
+                index.entries.push(e);
             }
             Err(e) => {
-                eprintln!("Error: {:?}", (e, entry));
+                index.skipped.push(Skipped {
+                    path: relative(&entry.path()),
+                    reason: e.to_string(),
+                });
             }
         }
     }
-    Ok(entries)
+    index.pruned_dirs = pruned.load(Ordering::Relaxed);
+    Ok(index)
+}
+
+/// Why a file is sent or not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendDecision {
+    New,
+    /// Size or modification time differs
+    Changed,
+    /// `--append`; the host copy is shorter, and taken as the first `offset` bytes
+    Append {
+        offset: u64,
+    },
+    /// Another path of a hard link being sent
+    LinkedToSent,
+    UpToDate,
+    /// Moved or copied from a host file gone from Android
+    FoundOnHost,
+    /// `--update`
+    NewerOnHost,
+    /// `--ignore-existing`
+    Existing,
+    /// `--existing`
+    NotExisting,
+}
+
+impl SendDecision {
+    pub fn sends(self) -> bool {
+        matches!(
+            self,
+            Self::New | Self::Changed | Self::Append { .. } | Self::LinkedToSent
+        )
+    }
+
+    /// Skipped because of `--update`, `--ignore-existing` or `--existing`
+    pub fn by_policy(self) -> bool {
+        matches!(self, Self::NewerOnHost | Self::Existing | Self::NotExisting)
+    }
+}
+
+impl Display for SendDecision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            Self::New => "new",
+            Self::Changed => "size or mtime differs",
+            Self::Append { .. } => "host copy is shorter, --append",
+            Self::LinkedToSent => "hard link of a sent file",
+            Self::UpToDate => "up to date",
+            Self::FoundOnHost => "found on the host under another path",
+            Self::NewerOnHost => "newer on the host, --update",
+            Self::Existing => "exists on the host, --ignore-existing",
+            Self::NotExisting => "not on the host, --existing",
+        };
+        f.write_str(description)
+    }
 }
 
+/// Decide on every entry whether to send it, keeping the order of `entries`.
+///
+/// Modification times are compared within `modify_window`.
 pub fn generate_send_list<P: AsRef<Path>>(
     entries: Vec<Entry>,
     dest_dir: P,
-) -> io::Result<Vec<Entry>> {
-    let ignore_mtime = mutex_lock!(CONFIG).as_ref().unwrap().ignore_mtime;
+    modify_window: Duration,
+) -> io::Result<Vec<(Entry, SendDecision)>> {
+    let config = mutex_lock!(CONFIG).clone().unwrap();
 
-    let mut send_list = Vec::new();
+    let mut decisions = Vec::new();
     for e in entries {
         let path = &e.path.0;
         let dest_file = dest_dir.as_ref().join(path);
-        let send: io::Result<bool> = (|| {
+        let decision: io::Result<SendDecision> = try {
             if !dest_file.exists() {
-                return Ok(true);
+                if config.existing {
+                    SendDecision::NotExisting
+                } else {
+                    SendDecision::New
+                }
+            } else if config.ignore_existing {
+                SendDecision::Existing
+            } else {
+                let metadata = dest_file.symlink_metadata()?;
+                let host_mtime = metadata.modified()?;
+                let same_mtime = mtime_distance(host_mtime, e.modified) <= modify_window;
+                if metadata.len() == e.size && (config.ignore_mtime || same_mtime) {
+                    SendDecision::UpToDate
+                } else if config.update && host_mtime > e.modified && !same_mtime {
+                    SendDecision::NewerOnHost
+                } else if config.append && metadata.len() < e.size {
+                    SendDecision::Append {
+                        offset: metadata.len(),
+                    }
+                } else {
+                    SendDecision::Changed
+                }
             }
+        };
+        decisions.push((e, decision?));
+    }
 
-            let metadata = dest_file.symlink_metadata()?;
-            if metadata.len() != e.size {
-                return Ok(true);
-            }
+    // a hard link is only sent along with the file it links to, so send its whole group
+    let sent_links = decisions
+        .iter()
+        .filter(|(x, decision)| x.nlink > 1 && decision.sends())
+        .map(|(x, _)| (x.dev, x.ino))
+        .collect::<HashSet<_>>();
+    for (x, decision) in &mut decisions {
+        if *decision == SendDecision::UpToDate && sent_links.contains(&(x.dev, x.ino)) {
+            *decision = SendDecision::LinkedToSent;
+        }
+    }
+    Ok(decisions)
+}
 
-            if !ignore_mtime && metadata.modified()? != e.modified {
-                return Ok(true);
-            }
+pub(crate) fn mtime_distance(a: SystemTime, b: SystemTime) -> Duration {
+    a.duration_since(b).unwrap_or_else(|e| e.duration())
+}
 
-            Ok(false)
-        })();
-        if send? {
-            send_list.push(e)
-        }
+/// `statfs` types of the filesystems keeping mtimes to 2 s
+const MSDOS_SUPER_MAGIC: u32 = 0x4d44;
+const EXFAT_SUPER_MAGIC: u32 = 0x2011bab0;
+
+/// The modify window for FAT and exFAT, which keep mtimes to 2 s
+pub const DEFAULT_MODIFY_WINDOW: Duration = Duration::from_secs(2);
+
+/// How far off the filesystem of `dir` may store modification times, like 2 s on FAT.
+///
+/// Told by the filesystem type, so nothing is written there; zero if `dir` doesn't exist yet.
+pub fn detect_mtime_granularity(dir: &Path) -> io::Result<Duration> {
+    if !dir.exists() {
+        return Ok(Duration::ZERO);
     }
-    Ok(send_list)
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read once filled in
+    let stat = unsafe {
+        if libc::statfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok(match stat.f_type as u32 {
+        MSDOS_SUPER_MAGIC | EXFAT_SUPER_MAGIC => DEFAULT_MODIFY_WINDOW,
+        _ => Duration::ZERO,
+    })
 }
 
 pub static ANDROID_TMP_DIR: Lazy<&Path> = Lazy::new(|| Path::new("/data/local/tmp"));
@@ -195,6 +480,11 @@ pub static ANDROID_ADB_SYNC_TMP_DIR: Lazy<&Path> =
     Lazy::new(|| Path::new("/data/local/tmp/adb-sync"));
 pub const ADB_EXE_NAME: &str = "adb";
 
+pub fn is_root() -> bool {
+    // SAFETY: always successful
+    unsafe { libc::geteuid() == 0 }
+}
+
 pub fn timestamp_ms() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -214,7 +504,8 @@ pub fn adb_command(subcommand: &str, args: &[&str]) -> io::Result<()> {
         .arg(subcommand)
         .args(args)
         .stdin(Stdio::null())
-        .stdout(Stdio::inherit())
+        // stdout is kept for reports like `--json`
+        .stdout(io::stderr())
         .stderr(Stdio::inherit())
         .spawn()?
         .wait()?;
@@ -228,13 +519,72 @@ pub fn adb_shell<S: AsRef<str>>(shell: S) -> io::Result<()> {
     adb_command("shell", &[shell.as_ref()])
 }
 
-pub fn adb_shell_run(binary_name: &str, args: &[&str]) -> io::Result<()> {
+/// Run an adb command and return its trimmed stdout.
+pub fn adb_output(args: &[&str]) -> io::Result<String> {
+    let output = Command::new(ADB_EXE_NAME)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("Failed adb execution"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().into())
+}
+
+/// Run one of the Android binaries through `adb shell`, without waiting, and capture
+/// the stdout.
+pub fn adb_shell_spawn(binary_name: &str, args: &[&str]) -> io::Result<Child> {
     let bin_path = ANDROID_ADB_SYNC_TMP_DIR.join(binary_name);
-    let mut joined_args = vec![assert_utf8_path!(bin_path)];
-    for &x in args {
-        joined_args.push(x);
+    Command::new(ADB_EXE_NAME)
+        .arg("shell")
+        .arg(assert_utf8_path!(bin_path))
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+}
+
+/// Android side of the readiness handshake
+pub fn report_listening(addr: SocketAddr) {
+    println!("{}{}", LISTENING_PREFIX, addr);
+}
+
+/// Host side of the readiness handshake
+///
+/// Wait until an Android server reports its address with [`LISTENING_PREFIX`], for at most
+/// `timeout`. All the other output lines are forwarded to stderr by the returned thread,
+/// which ends with the server.
+pub fn wait_for_listening(
+    stdout: ChildStdout,
+    timeout: Duration,
+) -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+    let (sender, receiver) = mpsc::channel();
+    let forwarder = spawn(move || {
+        let mut sender = Some(sender);
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            // `adb shell` may add carriage returns
+            let line = line.trim_end();
+            if let Some(addr) = line.strip_prefix(LISTENING_PREFIX)
+                && let Some(sender) = sender.take()
+            {
+                let _ = sender.send(addr.parse::<SocketAddr>());
+                continue;
+            }
+            eprintln!("{}", line);
+        }
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(addr) => Ok((addr?, forwarder)),
+        Err(RecvTimeoutError::Timeout) => Err(anyhow::anyhow!(
+            "Android server didn't get ready in {}",
+            humantime::format_duration(timeout)
+        )),
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(anyhow::anyhow!("Android server exited before it got ready"))
+        }
     }
-    adb_command("shell", &joined_args)
 }
 
 pub fn android_mktemp() -> io::Result<PathBuf> {
@@ -2,36 +2,185 @@
 
 use std::fs::create_dir_all;
 use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::{SocketAddr, TcpStream};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::thread::{sleep, spawn};
-use std::time::Duration;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, SystemTime};
 
-use anyhow::anyhow;
-use clap::Parser;
+use anyhow::{Context, anyhow};
+use bytesize::ByteSize;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
 use colored::Colorize;
-use log::{debug, info};
+use log::{info, warn};
 use readwrite::ReadWrite;
 
+use adb_sync::discovery::{Candidate, ScopedIp, check_connectivity};
+use adb_sync::filter;
+use adb_sync::filter::{CompiledRules, FilterRule, Preset};
+use adb_sync::hash::HashAlgorithm;
+use adb_sync::perms::Chmod;
 use adb_sync::stream::ReadWriteFlush;
-use adb_sync::stream::host::start;
+use adb_sync::stream::auth::AuthToken;
+use adb_sync::stream::crypto::{EncryptedStream, Role};
+use adb_sync::stream::host::{start, verify};
 use adb_sync::stream::protocol::SendConfig;
+use adb_sync::xattrs::XattrFilter;
 use adb_sync::{
-    ADB_EXE_NAME, ADB_SYNC_PORT, ANDROID_ADB_SYNC_TMP_DIR, ANDROID_CALL_NAME_GET_IP,
-    ANDROID_CALL_NAME_IP_CHECKER, ANDROID_CALL_NAME_STDIO_SERVER, ANDROID_CALL_NAME_TCP_SERVER,
-    ANDROID_CALL_NAMES, CONFIG, Config, IP_CHECKER_PORT, adb_command, adb_shell, adb_shell_run,
-    android_mktemp, assert_utf8_path, configure_log, mutex_lock, self_dirname,
+    ADB_EXE_NAME, ANDROID_ADB_SYNC_TMP_DIR, ANDROID_CALL_NAME_GET_IP, ANDROID_CALL_NAME_IP_CHECKER,
+    ANDROID_CALL_NAME_STDIO_SERVER, ANDROID_CALL_NAME_TCP_SERVER, ANDROID_CALL_NAMES, CONFIG,
+    Config, IndexFilter, Mode, adb_command, adb_output, adb_shell, adb_shell_spawn, android_mktemp,
+    assert_utf8_path, configure_log, is_root, mutex_lock, self_dirname, wait_for_listening,
 };
 
 const ANDROID_BIN_NAME: &str = "adb-sync-android";
 
 #[derive(clap::Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Subcommand>,
     /// Path of the source directory
-    pub android_dir: PathBuf,
+    #[arg(required = true)]
+    pub android_dir: Option<PathBuf>,
     /// Path of the destination directory
+    #[arg(required = true)]
+    pub host_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub common: CommonArgs,
+    /// Ignore modification time.
+    ///
+    /// Generate send list only by path and size.
+    #[arg(long, alias = "im")]
+    pub ignore_mtime: bool,
+    /// Append the verified digest of every received file to this file.
+    ///
+    /// Lines are `<hex>  <path>`, with paths relative to the receiving directory.
+    #[arg(long)]
+    pub digest_log: Option<PathBuf>,
+    /// Keep a manifest of the received files in `.adb-sync` of the receiving directory.
+    ///
+    /// It has a JSON inventory with sizes, modification times, digests and source devices,
+    /// and `*SUMS` files checkable by `sha256sum -c`, `b3sum -c` and the like.
+    #[arg(long)]
+    pub manifest: bool,
+    /// Preserve permissions.
+    #[arg(long, short = 'p')]
+    pub perms: bool,
+    /// Preserve the owner and group; only works as root.
+    #[arg(long)]
+    pub owner: bool,
+    /// Change the permissions of received files, like `D755,F644` or `u+x,go-w`.
+    ///
+    /// Uses the syntax of rsync, and implies `--perms` with the rewritten permissions.
+    #[arg(long)]
+    pub chmod: Option<Chmod>,
+    /// Preserve extended attributes, `security.selinux` included.
+    ///
+    /// Those the host can't set are kept in `.adb-sync/xattrs` of the receiving directory,
+    /// for `setfattr --restore`.
+    #[arg(long, short = 'X')]
+    pub xattrs: bool,
+    /// Only preserve xattrs of these namespaces, like `security,user`.
+    #[arg(long, requires = "xattrs")]
+    pub xattr_namespaces: Option<XattrFilter>,
+    /// Skip files whose host copy is newer.
+    #[arg(long, short = 'u')]
+    pub update: bool,
+    /// Skip files already on the host, even if they differ.
+    #[arg(long)]
+    pub ignore_existing: bool,
+    /// Only update files already on the host; don't create new ones.
+    #[arg(long)]
+    pub existing: bool,
+    /// Report whether every file is sent or skipped, and why.
+    #[arg(long, short = 'v')]
+    pub verbose: bool,
+    /// Only report what would be sent, without transferring anything.
+    #[arg(long, short = 'n')]
+    pub dry_run: bool,
+    /// Treat modification times this close as equal, like `2s` for FAT.
+    ///
+    /// Detected from the filesystem type of the receiving directory by default: 2s on FAT
+    /// and exFAT, and 0 elsewhere.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub modify_window: Option<Duration>,
+    /// Move host files to where they were moved on Android, instead of sending them again.
+    ///
+    /// New files are matched by size and mtime against host files gone from Android. A match
+    /// by a different file name is only taken if it's the only one.
+    #[arg(long)]
+    pub detect_renames: bool,
+    /// Confirm detected renames by hashing both copies.
+    #[arg(long, requires = "detect_renames")]
+    pub verify_renames: bool,
+    /// Only send what's past the end of host copies shorter than the Android files.
+    ///
+    /// For files that only grow, like logs. The digest of every such file still covers all
+    /// of it, so a host copy that isn't a prefix fails the transfer.
+    #[arg(long)]
+    pub append: bool,
+    /// Like `--append`, but first compare the end of every shorter host copy with Android,
+    /// and send the whole file if they differ.
+    #[arg(long)]
+    pub append_verify: bool,
+    /// Only send files at least this large, like `1MB` or `1 MiB`.
+    #[arg(long)]
+    pub min_size: Option<ByteSize>,
+    /// Only send files at most this large.
+    #[arg(long)]
+    pub max_size: Option<ByteSize>,
+    /// Only send files modified since then, like `7days` ago or `2024-01-31` (UTC).
+    #[arg(long, value_parser = parse_time_bound)]
+    pub newer_than: Option<SystemTime>,
+    /// Only send files modified before then, like `30days` ago or `2024-01-31 12:00:00` (UTC).
+    #[arg(long, value_parser = parse_time_bound)]
+    pub older_than: Option<SystemTime>,
+    /// Print a summary in JSON to stdout, in place of the received paths.
+    #[arg(long)]
+    pub json: bool,
+    /// Keep files matching this glob, even if a later rule excludes them.
+    ///
+    /// Includes and excludes are tried in the order given, and the first one matching
+    /// decides; files no rule matches are kept. A glob with a `/`, like `Android/data`, is
+    /// matched against the path relative to the source directory and its parent directories;
+    /// one without, like `*.jpg`, against every path component. Case is ignored.
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+    /// Leave out files matching this glob, unless an earlier rule includes them.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+    /// Add the rules of a preset after `--include` and `--exclude`; can be repeated.
+    ///
+    /// Presets are meant for syncing the shared storage root, like `/sdcard/`.
+    #[arg(long, value_enum)]
+    pub preset: Vec<Preset>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Subcommand {
+    /// Check the host copy still matches Android, without transferring anything.
+    ///
+    /// Android and the host both hash the files of the same size. Missing, size-mismatched
+    /// and content-mismatched files fail the check; files only on the host are just listed.
+    Verify(VerifyArgs),
+}
+
+#[derive(clap::Args)]
+pub struct VerifyArgs {
+    /// Path of the source directory
+    pub android_dir: PathBuf,
+    /// Path of the destination directory, as given when syncing
     pub host_dir: PathBuf,
+    #[command(flatten)]
+    pub common: CommonArgs,
+    /// Send the missing and mismatched files.
+    #[arg(long)]
+    pub repair: bool,
+}
+
+/// Arguments of both syncing and verifying
+#[derive(clap::Args)]
+pub struct CommonArgs {
     /// Search `adb-sync-android` in this path. Default to where `adb-sync` locates.
     #[arg(default_value = ".", long, alias = "absp")]
     pub android_bin_search_path: PathBuf,
@@ -51,44 +200,119 @@ pub struct Args {
     pub skip_failed: bool,
     /// Manually specify the Android IP instead of automatic detection.
     ///
-    /// Only used in TCP mode.
+    /// A link-local IPv6 address needs the zone of the host interface,
+    /// like `fe80::1%eth0`. Only used in TCP mode.
     #[arg(long)]
-    pub android_ip: Option<String>,
-    /// Ignore modification time.
+    pub android_ip: Option<ScopedIp>,
+    /// Encrypt the TCP connection.
     ///
-    /// Generate send list only by path and size.
-    #[arg(long, alias = "im")]
-    pub ignore_mtime: bool,
+    /// Keys are derived from the session token handed over through adb.
+    #[arg(long, conflicts_with = "no_tcp")]
+    pub encrypt: bool,
+    /// Port the Android TCP server listens on.
+    ///
+    /// Defaults to an ephemeral port chosen by Android.
+    #[arg(long, default_value = "0", conflicts_with = "no_tcp")]
+    pub port: u16,
+    /// Port the Android connectivity checker listens on while probing addresses.
+    ///
+    /// Defaults to an ephemeral port chosen by Android.
+    #[arg(long, default_value = "0", conflicts_with_all = ["no_tcp", "android_ip"])]
+    pub probe_port: u16,
+    /// How long to wait for Android servers to start listening.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    pub ready_timeout: Duration,
+    /// Hash verifying the content of every received file.
+    #[arg(long, value_enum, default_value_t)]
+    pub hash: HashAlgorithm,
 }
 
 pub fn main() -> anyhow::Result<()> {
     configure_log()?;
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let filter_rules = user_rules(&matches);
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let (android_dir, host_dir, common, mode) = match args.command {
+        Some(Subcommand::Verify(x)) => (
+            x.android_dir,
+            x.host_dir,
+            x.common,
+            Mode::Verify { repair: x.repair },
+        ),
+        None => (
+            args.android_dir.unwrap(),
+            args.host_dir.unwrap(),
+            args.common,
+            Mode::Sync,
+        ),
+    };
 
     // Like rsync, if the source path ends with a slash, put all the received files
     // under a directory with the same base name as the source path.
-    let real_dest_dir = if format!("{}", args.android_dir.display()).ends_with('/') {
-        args.host_dir.clone()
+    let real_dest_dir = if format!("{}", android_dir.display()).ends_with('/') {
+        host_dir.clone()
     } else {
-        args.host_dir.join(args.android_dir.file_name().unwrap())
+        host_dir.join(android_dir.file_name().unwrap())
     };
-    create_dir_all(&real_dest_dir)?;
+    if mode != (Mode::Verify { repair: false }) && !args.dry_run {
+        create_dir_all(&real_dest_dir)?;
+    }
 
-    info!("Source path: {}", args.android_dir.display());
-    info!("Destination path: {}", args.host_dir.display());
+    info!("Source path: {}", android_dir.display());
+    info!("Destination path: {}", host_dir.display());
     info!("Receive files at: {}", real_dest_dir.display());
 
+    let filter_rules = filter::expand_presets(filter_rules, &args.preset);
+    CompiledRules::new(&filter_rules).context("Invalid --include or --exclude")?;
+
+    if args.owner && !is_root() {
+        warn!("Not running as root; --owner is ignored");
+    }
+    let source_device = if args.manifest {
+        source_device()?
+    } else {
+        String::new()
+    };
     mutex_lock!(CONFIG).replace(Config {
         send_config: SendConfig {
-            path: args.android_dir,
-            skip_failed: args.skip_failed,
+            path: android_dir,
+            skip_failed: common.skip_failed,
+            hash: common.hash,
+            xattrs: args
+                .xattrs
+                .then(|| args.xattr_namespaces.unwrap_or_default()),
+            filter: IndexFilter {
+                rules: filter_rules,
+                min_size: args.min_size.map(|x| x.as_u64()),
+                max_size: args.max_size.map(|x| x.as_u64()),
+                newer_than: args.newer_than,
+                older_than: args.older_than,
+            },
         },
         dest_path: real_dest_dir,
         ignore_mtime: args.ignore_mtime,
+        digest_log: args.digest_log,
+        manifest: args.manifest,
+        source_device,
+        mode,
+        perms: args.perms,
+        owner: args.owner && is_root(),
+        chmod: args.chmod,
+        update: args.update,
+        ignore_existing: args.ignore_existing,
+        existing: args.existing,
+        verbose: args.verbose,
+        dry_run: args.dry_run,
+        modify_window: args.modify_window,
+        detect_renames: args.detect_renames,
+        verify_renames: args.verify_renames,
+        append: args.append || args.append_verify,
+        append_verify: args.append_verify,
+        json: args.json,
     });
 
     let android_binary = {
-        let sp = args.android_bin_search_path;
+        let sp = common.android_bin_search_path;
         if sp.is_relative() {
             self_dirname().join(sp).join(ANDROID_BIN_NAME)
         } else {
@@ -105,18 +329,18 @@ pub fn main() -> anyhow::Result<()> {
     info!("{}", "Preparing Android binaries...".cyan().bold());
     prepare_android_binaries(android_binary)?;
 
-    let android_ip = if args.no_tcp {
+    let android_ip = if common.no_tcp {
         None
     } else {
-        match args.android_ip {
-            None => get_connectable_ip()?,
-            Some(ip) => Some(ip.parse::<IpAddr>()?),
+        match common.android_ip {
+            None => get_connectable_ip(common.probe_port, common.ready_timeout)?,
+            Some(ip) => Some(ip.socket_addr(0)?),
         }
     };
 
     match android_ip {
         None => {
-            if args.no_stdio {
+            if common.no_stdio {
                 return Err(anyhow!(
                     "no_stdio is enabled. Won't fall back to this method."
                 ));
@@ -125,34 +349,75 @@ pub fn main() -> anyhow::Result<()> {
                 stdio_transfer()?;
             }
         }
-        Some(ip) => {
+        Some(addr) => {
             info!("Transfer via TCP");
-            info!("Use Android IP: {}", ip);
-            tcp_transfer(ip)?;
+            info!("Use Android IP: {}", addr.ip());
+            tcp_transfer(addr, common.port, common.encrypt, common.ready_timeout)?;
         }
     }
 
     Ok(())
 }
 
-fn tcp_transfer(ip: IpAddr) -> anyhow::Result<()> {
-    let android_child = spawn(|| {
-        adb_shell_run(ANDROID_CALL_NAME_TCP_SERVER, &[]).unwrap();
-    });
-    sleep(Duration::from_secs(1));
-    let config = mutex_lock!(CONFIG).clone().unwrap();
-
-    let tcp_stream = TcpStream::connect(SocketAddr::new(ip, ADB_SYNC_PORT))?;
+/// `addr` is the Android address as the host reaches it; its port is ignored.
+fn tcp_transfer(
+    mut addr: SocketAddr,
+    port: u16,
+    encrypt: bool,
+    ready_timeout: Duration,
+) -> anyhow::Result<()> {
+    let token = AuthToken::generate()?;
+    let token_hex = token.to_hex();
+    // Android resolves the zone of a link-local address by itself
+    let ip_string = addr.ip().to_string();
+    let port_string = port.to_string();
+    let mut server_args = vec![
+        "--token",
+        &token_hex,
+        "--bind",
+        &ip_string,
+        "--port",
+        &port_string,
+    ];
+    if encrypt {
+        server_args.push("--encrypt");
+    }
+    let mut android_child =
+        KillOnDrop(adb_shell_spawn(ANDROID_CALL_NAME_TCP_SERVER, &server_args)?);
+    let android_stdout = android_child.0.stdout.take().unwrap();
+    let (listening_addr, android_output) = wait_for_listening(android_stdout, ready_timeout)?;
+    addr.set_port(listening_addr.port());
+    let tcp_stream = TcpStream::connect(addr)?;
 
-    start(tcp_stream, config.send_config.clone(), &config.dest_path)?;
+    let stream: Box<dyn Duplex> = if encrypt {
+        Box::new(EncryptedStream::new(tcp_stream, &token, Role::Host)?)
+    } else {
+        Box::new(tcp_stream)
+    };
+    run_session(stream, Some(&token))?;
 
-    android_child.join().unwrap();
+    android_output.join().unwrap();
+    if !android_child.0.wait()?.success() {
+        return Err(anyhow!("Failed adb execution"));
+    }
     Ok(())
 }
 
-fn stdio_transfer() -> anyhow::Result<()> {
-    let config = mutex_lock!(CONFIG).clone().unwrap();
+trait Duplex: Read + Write {}
 
+impl<T: Read + Write> Duplex for T {}
+
+/// Kills and reaps the child when dropped, so no path leaves it running
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn stdio_transfer() -> anyhow::Result<()> {
     let mut child = Command::new("adb")
         .arg("shell")
         .arg(assert_utf8_path!(
@@ -165,10 +430,24 @@ fn stdio_transfer() -> anyhow::Result<()> {
     let process_stdin = child.stdin.take().unwrap();
     let process_stdout = child.stdout.take().unwrap();
     let stream = ReadWriteFlush(ReadWrite::new(process_stdout, process_stdin));
-    start(stream, config.send_config.clone(), &config.dest_path)
+    run_session(stream, None)
 }
 
-fn get_connectable_ip() -> anyhow::Result<Option<IpAddr>> {
+/// Sync or verify, as configured, over an established connection.
+fn run_session<S: Read + Write>(stream: S, token: Option<&AuthToken>) -> anyhow::Result<()> {
+    let config = mutex_lock!(CONFIG).clone().unwrap();
+    match config.mode {
+        Mode::Sync => start(stream, config.send_config, &config.dest_path, token),
+        Mode::Verify { repair } => {
+            verify(stream, config.send_config, &config.dest_path, token, repair)
+        }
+    }
+}
+
+fn get_connectable_ip(
+    probe_port: u16,
+    ready_timeout: Duration,
+) -> anyhow::Result<Option<SocketAddr>> {
     let mut child = Command::new(ADB_EXE_NAME)
         .arg("shell")
         .arg(assert_utf8_path!(
@@ -185,47 +464,70 @@ fn get_connectable_ip() -> anyhow::Result<Option<IpAddr>> {
         return Err(anyhow!("Failed adb execution"));
     }
 
-    spawn(|| {
-        adb_shell_run(ANDROID_CALL_NAME_IP_CHECKER, &[]).unwrap();
-    });
-    sleep(Duration::from_secs(1));
-    let ips = output.lines().filter(|x| !x.is_empty()).collect::<Vec<_>>();
-    Ok(check_connectivity(&ips))
+    let port_string = probe_port.to_string();
+    let mut checker_child =
+        adb_shell_spawn(ANDROID_CALL_NAME_IP_CHECKER, &["--port", &port_string])?;
+    let checker_stdout = checker_child.stdout.take().unwrap();
+    let result: anyhow::Result<Option<SocketAddr>> = try {
+        let (checker_addr, _) = wait_for_listening(checker_stdout, ready_timeout)?;
+        let candidates = output
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(str::parse)
+            .collect::<anyhow::Result<Vec<Candidate>>>()?;
+        let probes = check_connectivity(candidates, checker_addr.port());
+        probes.into_iter().next().map(|x| {
+            info!("Selected {} (rtt: {:?})", x.candidate, x.rtt);
+            x.addr
+        })
+    };
+    // probing is over; don't leave the checker waiting for its own timeout
+    let _ = checker_child.kill();
+    let _ = checker_child.wait();
+    // like finding no reachable address; whether stdio may be used is up to the caller
+    result.or_else(|e| {
+        warn!("Failed to probe the Android addresses: {}", e);
+        Ok(None)
+    })
 }
 
-fn check_connectivity(ips: &[&str]) -> Option<IpAddr> {
-    let mut handlers = Vec::new();
-    for ip in ips {
-        let ip_addr = ip.parse().unwrap();
-        debug!("check ip: {}", ip_addr);
-        let handler = spawn(move || {
-            let result: anyhow::Result<IpAddr> = try {
-                let mut listener = TcpStream::connect_timeout(
-                    &SocketAddr::new(ip_addr, IP_CHECKER_PORT),
-                    Duration::from_secs(1),
-                )?;
-                listener.set_write_timeout(Some(Duration::from_secs(1)))?;
-                listener.set_read_timeout(Some(Duration::from_secs(1)))?;
-                const ECHO_MESSAGE: [u8; 12] = *b"Please echo\n";
-                listener.write_all(&ECHO_MESSAGE)?;
-                let mut buf = [0_u8; ECHO_MESSAGE.len()];
-                listener.read_exact(&mut buf)?;
-                if ECHO_MESSAGE == buf {
-                    ip_addr
-                } else {
-                    Err(anyhow!("Not equal"))?
-                }
-            };
-            result.ok()
-        });
-        handlers.push(handler);
-    }
-    for x in handlers {
-        if let Some(ip) = x.join().ok().flatten() {
-            return Some(ip);
+/// `--include` and `--exclude`, in the order given
+fn user_rules(matches: &ArgMatches) -> Vec<FilterRule> {
+    let indexed = |id: &str| -> Vec<(usize, String)> {
+        match (matches.indices_of(id), matches.get_many::<String>(id)) {
+            (Some(indices), Some(globs)) => indices.zip(globs.cloned()).collect(),
+            _ => Vec::new(),
         }
+    };
+    let includes = indexed("include")
+        .into_iter()
+        .map(|(i, x)| (i, FilterRule::Include(x)));
+    let excludes = indexed("exclude")
+        .into_iter()
+        .map(|(i, x)| (i, FilterRule::Exclude(x)));
+    let mut rules = includes.chain(excludes).collect::<Vec<_>>();
+    rules.sort_by_key(|(index, _)| *index);
+    rules.into_iter().map(|(_, x)| x).collect()
+}
+
+/// A duration back from now, like `7days`, or a date in UTC, like `2024-01-31`.
+fn parse_time_bound(s: &str) -> anyhow::Result<SystemTime> {
+    if let Ok(x) = humantime::parse_duration(s) {
+        return SystemTime::now()
+            .checked_sub(x)
+            .ok_or_else(|| anyhow!("Too far back: {}", s));
     }
-    None
+    humantime::parse_rfc3339_weak(s)
+        .or_else(|_| humantime::parse_rfc3339_weak(&format!("{} 00:00:00", s)))
+        .map_err(|_| anyhow!("Neither a duration nor a date: {}", s))
+}
+
+/// Like `Pixel 7 (2A111FDH200ABC)`
+fn source_device() -> anyhow::Result<String> {
+    let serial = adb_output(&["get-serialno"])?;
+    let model = adb_output(&["shell", "getprop", "ro.product.model"])?;
+    Ok(format!("{} ({})", model, serial))
 }
 
 pub fn prepare_android_binaries<P: AsRef<Path>>(android_binary: P) -> anyhow::Result<()> {
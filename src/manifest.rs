@@ -0,0 +1,126 @@
+//! Inventory of the received files, kept in the destination directory
+//!
+//! `manifest.json` is the source of truth, merged across runs; the `*SUMS` files are
+//! regenerated from it, one per hash algorithm, and can be checked from the destination
+//! directory with e.g. `sha256sum -c .adb-sync/SHA256SUMS`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::HashAlgorithm;
+use crate::renames::Rename;
+use crate::send_stream::ReceivedFile;
+
+pub const MANIFEST_DIR: &str = ".adb-sync";
+const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    /// Keyed by the path relative to the destination directory
+    files: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ManifestEntry {
+    size: u64,
+    /// Modification time on Android, in RFC 3339
+    mtime: String,
+    hash: HashAlgorithm,
+    digest: String,
+    source_device: String,
+}
+
+/// Merge `files` into the manifest of `dest_dir`, carry the entries of `renamed` files over
+/// to their new paths, drop the entries whose files are gone, and rewrite the checksum files.
+pub fn update(
+    dest_dir: &Path,
+    files: &[ReceivedFile],
+    renamed: &[Rename],
+    hash: HashAlgorithm,
+    source_device: &str,
+) -> anyhow::Result<()> {
+    let manifest_dir = dest_dir.join(MANIFEST_DIR);
+    fs::create_dir_all(&manifest_dir)?;
+    let manifest_path = manifest_dir.join(MANIFEST_FILE);
+
+    let mut manifest = if manifest_path.exists() {
+        let json = fs::read(&manifest_path)?;
+        serde_json::from_slice::<Manifest>(&json)
+            .with_context(|| format!("Invalid manifest: {}", manifest_path.display()))?
+    } else {
+        Manifest::default()
+    };
+
+    for x in renamed {
+        if let Some(entry) = manifest.files.get(&*x.from.to_string_lossy()) {
+            let entry = entry.clone();
+            manifest.files.insert(x.to.to_string_lossy().into(), entry);
+        }
+    }
+    for x in files {
+        manifest.files.insert(
+            x.path.to_string_lossy().into(),
+            ManifestEntry {
+                size: x.size,
+                mtime: format_mtime(x.mtime),
+                hash,
+                digest: hex::encode(&x.digest),
+                source_device: source_device.into(),
+            },
+        );
+    }
+    manifest.files.retain(|path, _| {
+        dest_dir
+            .join(path)
+            .symlink_metadata()
+            .is_ok_and(|x| x.is_file())
+    });
+
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    write_replacing(&manifest_path, |w| Ok(w.write_all(&json)?))?;
+
+    let mut sums = BTreeMap::<HashAlgorithm, Vec<(&String, &ManifestEntry)>>::new();
+    for (path, entry) in &manifest.files {
+        sums.entry(entry.hash).or_default().push((path, entry));
+    }
+    for &algorithm in HashAlgorithm::value_variants() {
+        let sums_path = manifest_dir.join(algorithm.sums_file_name());
+        match sums.get(&algorithm) {
+            Some(entries) => write_replacing(&sums_path, |w| {
+                for (path, entry) in entries {
+                    writeln!(w, "{}  {}", entry.digest, path)?;
+                }
+                Ok(())
+            })?,
+            None if sums_path.exists() => fs::remove_file(&sums_path)?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+fn format_mtime(mtime: SystemTime) -> String {
+    humantime::format_rfc3339_nanos(mtime).to_string()
+}
+
+/// Write through a temporary file, so an interrupted run never leaves a truncated one.
+pub(crate) fn write_replacing<F>(path: &Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> anyhow::Result<()>,
+{
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    write(&mut writer)?;
+    writer.flush()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! `--chmod` rules, in the syntax of rsync
+//!
+//! A comma-separated list of `[DF]` + an octal mode or `[ugoa]*[-+=][rwxXst]*`, like
+//! `D755,F644` or `u+x,go-w`. Rules prefixed by `D` only apply to directories, and by `F`
+//! only to regular files.
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+const PERMISSION_BITS: u32 = 0o7777;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    All,
+    Files,
+    Directories,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Set(u32),
+    Symbolic { who: u32, op: char, perms: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    target: Target,
+    action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chmod(Vec<Rule>);
+
+impl FromStr for Chmod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(parse_rule)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+fn parse_rule(rule: &str) -> anyhow::Result<Rule> {
+    let invalid = || anyhow!("Invalid chmod rule: {}", rule);
+    let (target, rest) = match rule.as_bytes().first() {
+        Some(b'D') => (Target::Directories, &rule[1..]),
+        Some(b'F') => (Target::Files, &rule[1..]),
+        _ => (Target::All, rule),
+    };
+
+    if !rest.is_empty() && rest.bytes().all(|x| x.is_ascii_digit()) {
+        let mode = u32::from_str_radix(rest, 8).map_err(|_| invalid())?;
+        if mode > PERMISSION_BITS {
+            return Err(invalid());
+        }
+        return Ok(Rule {
+            target,
+            action: Action::Set(mode),
+        });
+    }
+
+    let op_index = rest.find(['+', '-', '=']).ok_or_else(invalid)?;
+    let (who, perms) = rest.split_at(op_index);
+    let (op, perms) = perms.split_at(1);
+    let mut who_mask = 0;
+    for x in who.chars() {
+        who_mask |= match x {
+            'u' => 0o4700,
+            'g' => 0o2070,
+            'o' => 0o1007,
+            'a' => PERMISSION_BITS,
+            _ => return Err(invalid()),
+        };
+    }
+    if who.is_empty() {
+        who_mask = PERMISSION_BITS;
+    }
+    if !perms.chars().all(|x| "rwxXst".contains(x)) {
+        return Err(invalid());
+    }
+    Ok(Rule {
+        target,
+        action: Action::Symbolic {
+            who: who_mask,
+            op: op.chars().next().unwrap(),
+            perms: perms.into(),
+        },
+    })
+}
+
+impl Chmod {
+    /// Apply the rules on the permission bits of `mode`, in order.
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let mut mode = mode & PERMISSION_BITS;
+        for rule in &self.0 {
+            match rule.target {
+                Target::Files if is_dir => continue,
+                Target::Directories if !is_dir => continue,
+                _ => {}
+            }
+            match &rule.action {
+                Action::Set(x) => mode = *x,
+                Action::Symbolic { who, op, perms } => {
+                    let mut bits = 0;
+                    for x in perms.chars() {
+                        bits |= match x {
+                            'r' => 0o444,
+                            'w' => 0o222,
+                            'x' => 0o111,
+                            'X' if is_dir || mode & 0o111 != 0 => 0o111,
+                            's' => 0o6000,
+                            't' => 0o1000,
+                            _ => 0,
+                        };
+                    }
+                    let bits = bits & who;
+                    mode = match op {
+                        '+' => mode | bits,
+                        '-' => mode & !bits,
+                        _ => (mode & !who) | bits,
+                    };
+                }
+            }
+        }
+        mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(rules: &str, mode: u32, is_dir: bool) -> u32 {
+        rules.parse::<Chmod>().unwrap().apply(mode, is_dir)
+    }
+
+    #[test]
+    fn refuses_invalid_rules() {
+        for rules in [
+            "", "D", "F,", "Q+x", "u+q", "uw", "8", "17777", "D755,", "u+x;g-w",
+        ] {
+            assert!(rules.parse::<Chmod>().is_err(), "{}", rules);
+        }
+    }
+
+    #[test]
+    fn octal_rules_target_files_or_directories() {
+        assert_eq!(apply("D755,F644", 0o700, true), 0o755);
+        assert_eq!(apply("D755,F644", 0o600, false), 0o644);
+        assert_eq!(apply("640", 0o777, true), 0o640);
+    }
+
+    #[test]
+    fn symbolic_rules_apply_in_order() {
+        assert_eq!(apply("u+x,go-w", 0o666, false), 0o744);
+        assert_eq!(apply("u=rw", 0o777, false), 0o677);
+        assert_eq!(apply("+x", 0o644, false), 0o755);
+        assert_eq!(apply("a=r,u+w", 0o777, false), 0o644);
+        assert_eq!(apply("Fg+s", 0o755, false), 0o2755);
+        assert_eq!(apply("Fg+s", 0o755, true), 0o755);
+    }
+
+    #[test]
+    fn capital_x_only_for_directories_and_executables() {
+        assert_eq!(apply("a+X", 0o644, false), 0o644);
+        assert_eq!(apply("a+X", 0o744, false), 0o755);
+        assert_eq!(apply("a+X", 0o644, true), 0o755);
+    }
+
+    #[test]
+    fn file_type_bits_are_dropped() {
+        assert_eq!(apply("u+x", 0o100644, false), 0o744);
+    }
+}
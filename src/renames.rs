@@ -0,0 +1,136 @@
+//! Rename detection
+//!
+//! Files moved around on Android show up as new paths, while their host copies stay under
+//! the old ones. Matching them by size and mtime, the host moves its copies into place
+//! instead of transferring them again.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use filetime::FileTime;
+
+use crate::manifest::MANIFEST_DIR;
+use crate::send_stream::{confine_path, create_dirs_nofollow};
+use crate::{Entry, Skipped, mtime_distance};
+
+#[derive(Debug, Clone)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// `from` is already the destination of an earlier rename, so it's copied
+    pub copy: bool,
+}
+
+/// Host files gone from Android, grouped by size
+pub struct Matcher {
+    orphans: HashMap<u64, Vec<Entry>>,
+    modify_window: Duration,
+}
+
+impl Matcher {
+    pub fn new(orphans: Vec<Entry>, modify_window: Duration) -> Self {
+        let mut by_size = HashMap::<u64, Vec<Entry>>::new();
+        for x in orphans {
+            by_size.entry(x.size).or_default().push(x);
+        }
+        Self {
+            orphans: by_size,
+            modify_window,
+        }
+    }
+
+    /// Orphans with the size and mtime of `entry`, those with its file name first
+    pub fn candidates(&self, entry: &Entry) -> Vec<&Entry> {
+        let Some(same_size) = self.orphans.get(&entry.size) else {
+            return Vec::new();
+        };
+        let mut candidates = same_size
+            .iter()
+            .filter(|x| mtime_distance(x.modified, entry.modified) <= self.modify_window)
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|x| x.path.0.file_name() != entry.path.0.file_name());
+        candidates
+    }
+
+    /// Pick a host file for each of `new_entries`.
+    ///
+    /// With `confirm`, the first candidate it accepts is taken. Without, only a candidate
+    /// with the same file name or a single one is, so no file is guessed.
+    pub fn plan<F>(&self, new_entries: &[&Entry], mut confirm: Option<F>) -> Vec<Rename>
+    where
+        F: FnMut(&Entry, &Entry) -> bool,
+    {
+        // orphan -> where it's moved to
+        let mut moved = HashMap::<&Path, &Path>::new();
+        let mut renames = Vec::new();
+        for entry in new_entries {
+            let candidates = self.candidates(entry);
+            let chosen = match &mut confirm {
+                Some(confirm) => candidates.into_iter().find(|x| confirm(entry, x)),
+                None => match candidates.as_slice() {
+                    [first, ..] if first.path.0.file_name() == entry.path.0.file_name() => {
+                        Some(*first)
+                    }
+                    [only] => Some(*only),
+                    _ => None,
+                },
+            };
+            let Some(orphan) = chosen else {
+                continue;
+            };
+            let rename = match moved.get(orphan.path.0.as_path()) {
+                Some(&first) => Rename {
+                    from: first.into(),
+                    to: entry.path.0.clone(),
+                    copy: true,
+                },
+                None => {
+                    moved.insert(&orphan.path.0, &entry.path.0);
+                    Rename {
+                        from: orphan.path.0.clone(),
+                        to: entry.path.0.clone(),
+                        copy: false,
+                    }
+                }
+            };
+            renames.push(rename);
+        }
+        renames
+    }
+}
+
+/// Host files not in `android_paths`, excluding the bookkeeping of adb-sync
+///
+/// `host_entries` must be indexed with the filter Android used, and the paths Android
+/// `skipped` are left out as well, so only files really gone from Android are moved.
+pub fn orphans(
+    host_entries: Vec<Entry>,
+    android_paths: &HashSet<&Path>,
+    skipped: &[Skipped],
+) -> Vec<Entry> {
+    host_entries
+        .into_iter()
+        .filter(|x| !x.path.0.starts_with(MANIFEST_DIR))
+        .filter(|x| !android_paths.contains(x.path.0.as_path()))
+        .filter(|x| !skipped.iter().any(|s| x.path.0.starts_with(&s.path.0)))
+        .collect()
+}
+
+/// Move or copy a file within `dest_dir`, keeping its mtime.
+pub fn apply(dest_dir: &Path, rename: &Rename) -> anyhow::Result<()> {
+    let from = confine_path(dest_dir, &rename.from)?;
+    let to = confine_path(dest_dir, &rename.to)?;
+    if let Some(parent) = rename.to.parent() {
+        create_dirs_nofollow(dest_dir, parent)?;
+    }
+    if rename.copy {
+        fs::copy(&from, &to)?;
+        let mtime = from.symlink_metadata()?.modified()?;
+        filetime::set_file_mtime(&to, FileTime::from(mtime))?;
+    } else {
+        fs::rename(&from, &to)?;
+    }
+    Ok(())
+}
@@ -1,19 +1,36 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::fs::Permissions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::time::SystemTime;
 use std::{fs, io};
 
 use ::crc as crc_lib;
-use anyhow::anyhow;
+use anyhow::{Context, anyhow};
 use bincode::{Decode, Encode};
 use byteorder::{LE, ReadBytesExt, WriteBytesExt};
 use filetime::FileTime;
 
-use crate::crc;
-use crate::crc::write::CrcFilter;
+use crate::hash::{HashAlgorithm, Hasher};
+use crate::perms::Chmod;
+use crate::stream::protocol::SendItem;
 use crate::unix_path::UnixPath;
-use crate::{TryReadExact, bincode_config};
+use crate::xattrs;
+use crate::xattrs::{Xattr, XattrFilter};
+use crate::{PathContext, Skipped, TryReadExact, bincode_config, is_root};
+
+/// Maximum size of a content chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// In place of a chunk length, tells the sender gave up on the file
+const ABORT_CHUNK: u32 = 0xFFFFFFFF;
+
+/// In place of a chunk length, starts a run of zeros the sender found a hole for
+const HOLE_CHUNK: u32 = 0xFFFFFFFE;
 
 #[derive(Encode, Decode)]
 pub struct Header {
@@ -21,24 +38,78 @@ pub struct Header {
     pub file_type: FileType,
     pub mtime: SystemTime,
     pub file_size: u64,
+    /// `st_mode`, with the file type bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Only collected when asked for
+    pub xattrs: Vec<Xattr>,
+    /// For `HardLink`, the regular file sent earlier in the stream it links to
+    pub link_target: Option<UnixPath>,
+    /// Content starts here; the host already has the bytes before
+    pub offset: u64,
 }
 
-#[derive(Encode, Decode, Copy, Clone)]
+#[derive(Encode, Decode, Copy, Clone, PartialEq, Eq)]
 pub enum FileType {
     RegularFile,
     Directory,
+    HardLink,
+}
+
+/// Whether a regular file stayed the same while being sent
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordStatus {
+    Consistent = 0,
+    /// The file grew, shrank or was modified while being read. The content may be
+    /// a mix of the old and new versions, and zero-padded if it shrank.
+    Changed = 1,
+}
+
+impl RecordStatus {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Consistent),
+            1 => Some(Self::Changed),
+            _ => None,
+        }
+    }
 }
 
 pub struct SendStream<W: Write> {
     writer: W,
+    hash: HashAlgorithm,
+    xattrs: Option<XattrFilter>,
+    skipped: Vec<Skipped>,
+    /// First path sent of every (dev, inode) with more than one link
+    links: HashMap<(u64, u64), UnixPath>,
 }
 
 impl<W> SendStream<W>
 where
     W: Write,
 {
-    pub fn new(writer: W) -> Self {
-        Self { writer }
+    pub fn new(writer: W, hash: HashAlgorithm, xattrs: Option<XattrFilter>) -> Self {
+        Self {
+            writer,
+            hash,
+            xattrs,
+            skipped: Vec::new(),
+            links: HashMap::new(),
+        }
+    }
+
+    /// Files left out of the stream so far
+    pub fn take_skipped(&mut self) -> Vec<Skipped> {
+        std::mem::take(&mut self.skipped)
+    }
+
+    fn skip(&mut self, path: &Path, reason: impl ToString) {
+        self.skipped.push(Skipped {
+            path: path.into(),
+            reason: reason.to_string(),
+        });
     }
 }
 
@@ -48,36 +119,116 @@ where
 ///   \[ Record1 | Record2 | ... \]
 ///
 /// - Record structure:
-///   \[ HeaderLength (u32) | Header | FileContent | Checksum (u32) \]
+///   \[ HeaderLength (u32) | Header | FileContent | Status (u8) | Digest | Checksum (u32) \]
 ///
 ///   When `HeaderLength` is 0xFFFFFFFF, it indicates EOF.
+///
+///   `FileContent`, `Status` and `Digest` are only present for regular files. `Status` tells
+///   if the file changed while it was read (see [`RecordStatus`]). `Digest` is the hash of
+///   the content, with the algorithm negotiated in `SendConfig`. `Checksum` covers the
+///   header, the status and the digest.
+///
+///   Once a file with several links is sent, its other paths are sent as `HardLink` records,
+///   without content.
+///
+/// - FileContent structure:
+///   \[ Chunk1 | Chunk2 | ... \]
+///
+///   Chunks add up to exactly `file_size - offset` bytes; none is longer than [`CHUNK_SIZE`].
+///   `Digest` still covers the whole file, the bytes before `offset` included.
+///
+/// - Chunk structure:
+///   \[ ChunkLength (u32) | Data | ChunkChecksum (u32) \]
+///
+///   When `ChunkLength` is 0xFFFFFFFF, the sender failed to read the file, and the record
+///   ends right there, without `Status` and `Checksum`.
+///
+/// - Hole chunk structure:
+///   \[ 0xFFFFFFFE (u32) | HoleLength (u64) | ChunkChecksum (u32) \]
+///
+///   Stands for `HoleLength` zeros of a sparse file, which the receiver leaves as a hole.
+///   `ChunkChecksum` covers `HoleLength`.
 impl<W> SendStream<W>
 where
     W: Write,
 {
-    pub fn append_file<P: AsRef<Path>>(&mut self, header_path: P, file_path: P) -> io::Result<()> {
+    /// Send the content of a regular file from `offset`, or all of it if it's shorter now.
+    pub fn append_file<P: AsRef<Path>>(
+        &mut self,
+        header_path: P,
+        file_path: P,
+        offset: u64,
+    ) -> io::Result<()> {
         let header_path = header_path.as_ref();
-        let metadata = file_path.as_ref().symlink_metadata()?;
+        // a file vanishing or becoming unreadable since indexing only costs this record
+        let metadata = match file_path.as_ref().symlink_metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                self.skip(header_path, e);
+                return Ok(());
+            }
+        };
+        let link_key = (metadata.dev(), metadata.ino());
+        let link_target = if metadata.is_file() && metadata.nlink() > 1 {
+            self.links.get(&link_key).cloned()
+        } else {
+            None
+        };
 
-        if metadata.is_file() && File::open(&file_path).is_err() {
-            // skip this bad file
-            eprintln!("Skip bad file: {}", file_path.as_ref().display());
-            return Ok(());
+        let mut file = None;
+        if metadata.is_file() && link_target.is_none() {
+            match File::open(&file_path) {
+                Ok(f) => file = Some(f),
+                Err(e) => {
+                    // skip this bad file
+                    self.skip(header_path, e);
+                    return Ok(());
+                }
+            }
         }
 
-        let (file_type, file_size) = if metadata.is_file() {
+        let (file_type, file_size) = if link_target.is_some() {
+            (FileType::HardLink, 0)
+        } else if metadata.is_file() {
             (FileType::RegularFile, metadata.len())
         } else if metadata.is_dir() {
             (FileType::Directory, 0)
         } else {
-            eprintln!("Skip: {}", header_path.display());
+            self.skip(header_path, "Not a regular file");
             return Ok(());
         };
+        let xattrs = match &self.xattrs {
+            // a hard link shares them with its target
+            Some(filter) if file_type != FileType::HardLink => {
+                match xattrs::collect(file_path.as_ref(), filter) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        self.skip(header_path, format!("Failed to read xattrs: {}", e));
+                        return Ok(());
+                    }
+                }
+            }
+            _ => Vec::new(),
+        };
+        let content_offset = if offset <= file_size { offset } else { 0 };
+        let mtime = match metadata.modified() {
+            Ok(t) => t,
+            Err(e) => {
+                self.skip(header_path, e);
+                return Ok(());
+            }
+        };
         let header = Header {
             file_type,
-            mtime: metadata.modified()?,
+            mtime,
             path: header_path.into(),
             file_size,
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            xattrs,
+            link_target,
+            offset: content_offset,
         };
         let header_data = bincode::encode_to_vec(header, bincode_config()).unwrap();
         self.writer.write_u32::<LE>(header_data.len() as u32)?;
@@ -88,15 +239,81 @@ where
                 let crc = create_crc();
                 let mut digest = crc.digest();
                 digest.update(&header_data);
+                let mut hasher = self.hash.hasher();
 
-                let mut crc_filter = crc::write::CrcFilter::new(&mut digest, &mut self.writer);
-                let mut file = File::open(file_path)?;
-                io::copy(&mut file, &mut crc_filter)?;
-                crc_filter.flush()?;
+                let mut file = file.unwrap();
+                // the host has these bytes, but the digest covers them too
+                match io::copy(&mut (&file).take(content_offset), &mut hasher) {
+                    Ok(x) if x == content_offset => {}
+                    Ok(_) => return self.abort_record(header_path, "Shrank while being read"),
+                    Err(e) => return self.abort_record(header_path, e),
+                }
+                let mut buf = vec![0_u8; CHUNK_SIZE];
+                // only look for holes if some blocks are missing
+                let sparse = metadata.blocks() * 512 < file_size;
+                let mut offset = content_offset;
+                let mut data_end = if sparse { offset } else { file_size };
+                let mut shrunk = false;
+                while offset < file_size {
+                    if offset >= data_end {
+                        match next_data_region(&file, offset) {
+                            Ok(region) => {
+                                let (start, end) = region.unwrap_or((file_size, file_size));
+                                let start = start.min(file_size);
+                                if start > offset {
+                                    self.write_hole(start - offset, &mut hasher)?;
+                                    offset = start;
+                                }
+                                data_end = end.min(file_size);
+                            }
+                            // no hole support; send the rest as it reads
+                            Err(_) => data_end = file_size,
+                        }
+                        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+                            return self.abort_record(header_path, e);
+                        }
+                        continue;
+                    }
+
+                    let chunk = &mut buf[..(data_end - offset).min(CHUNK_SIZE as u64) as usize];
+                    let read = match file.try_read_exact(chunk) {
+                        Ok(r) => r,
+                        Err(e) => return self.abort_record(header_path, e),
+                    };
+                    if read < chunk.len() {
+                        // keep the stream in sync with the header
+                        shrunk = true;
+                        chunk[read..].fill(0);
+                    }
+                    self.writer.write_u32::<LE>(chunk.len() as u32)?;
+                    self.writer.write_all(chunk)?;
+                    self.writer.write_u32::<LE>(crc.checksum(chunk))?;
+                    hasher.update(chunk);
+                    offset += chunk.len() as u64;
+                }
+                // if the file can't even tell, it can't be trusted to be the same
+                let grown = file.read(&mut [0_u8; 1]).map_or(true, |x| x != 0);
+                let rewritten = file
+                    .metadata()
+                    .and_then(|x| x.modified())
+                    .map_or(true, |x| x != mtime);
+                let status = if shrunk || grown || rewritten {
+                    RecordStatus::Changed
+                } else {
+                    RecordStatus::Consistent
+                };
+                self.writer.write_u8(status as u8)?;
+                digest.update(&[status as u8]);
+                let content_digest = hasher.finalize();
+                self.writer.write_all(&content_digest)?;
+                digest.update(&content_digest);
                 let checksum = digest.finalize();
                 self.writer.write_u32::<LE>(checksum)?;
+                if metadata.nlink() > 1 {
+                    self.links.insert(link_key, header_path.into());
+                }
             }
-            FileType::Directory => {
+            FileType::Directory | FileType::HardLink => {
                 let crc = crc_lib::Crc::<u32>::new(&crc_lib::CRC_32_CKSUM);
                 let mut digest = crc.digest();
                 digest.update(&header_data);
@@ -107,6 +324,23 @@ where
         Ok(())
     }
 
+    /// End the record early, as its header is already out, and keep going.
+    fn abort_record(&mut self, path: &Path, reason: impl ToString) -> io::Result<()> {
+        self.writer.write_u32::<LE>(ABORT_CHUNK)?;
+        self.skip(path, reason);
+        Ok(())
+    }
+
+    fn write_hole(&mut self, length: u64, hasher: &mut Hasher) -> io::Result<()> {
+        let length_data = length.to_le_bytes();
+        self.writer.write_u32::<LE>(HOLE_CHUNK)?;
+        self.writer.write_all(&length_data)?;
+        self.writer
+            .write_u32::<LE>(create_crc().checksum(&length_data))?;
+        hash_zeros(hasher, length);
+        Ok(())
+    }
+
     fn write_eof(&mut self) -> io::Result<()> {
         self.writer.write_u32::<LE>(0xFFFFFFFF)
     }
@@ -116,6 +350,38 @@ pub fn create_crc() -> crc_lib::Crc<u32> {
     crc_lib::Crc::<u32>::new(&crc_lib::CRC_32_CKSUM)
 }
 
+/// Find the first data region of `file` at or after `offset`, as `(start, end)`.
+///
+/// `None` if only a hole is left. Moves the file position.
+fn next_data_region(file: &File, offset: u64) -> io::Result<Option<(u64, u64)>> {
+    let seek = |offset: u64, whence: libc::c_int| -> io::Result<u64> {
+        let offset = libc::off_t::try_from(offset)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // SAFETY: `file` is open for the whole call
+        let position = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+        if position < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(position as u64)
+    };
+    let start = match seek(offset, libc::SEEK_DATA) {
+        Ok(x) => x,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some((start, seek(start, libc::SEEK_HOLE)?)))
+}
+
+/// Feed `length` zeros to `hasher`, as reading a hole would.
+fn hash_zeros(hasher: &mut Hasher, mut length: u64) {
+    let zeros = [0_u8; CHUNK_SIZE];
+    while length > 0 {
+        let n = length.min(CHUNK_SIZE as u64) as usize;
+        hasher.update(&zeros[..n]);
+        length -= n as u64;
+    }
+}
+
 impl<W: Write> Drop for SendStream<W> {
     fn drop(&mut self) {
         let _ = self.writer.flush();
@@ -127,9 +393,9 @@ impl<W: Write> Drop for SendStream<W> {
 pub fn write_send_list_to_stream<P, W, F>(
     stream: &mut SendStream<W>,
     android_dir: P,
-    send_list: impl ExactSizeIterator<Item = UnixPath>,
+    send_list: impl ExactSizeIterator<Item = SendItem>,
     mut callback: F,
-) -> io::Result<()>
+) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
     W: Write,
@@ -137,24 +403,250 @@ where
 {
     let send_list_size = send_list.len();
 
-    for (index, b) in send_list.enumerate() {
-        let relative_path = b.0.as_path();
+    for (index, item) in send_list.enumerate() {
+        let relative_path = item.path.0.as_path();
         if relative_path.components().count() == 0 {
+            stream.skip(relative_path, "Empty path");
             continue;
         }
         let path = android_dir.as_ref().join(relative_path);
 
         callback(relative_path, (index, send_list_size));
-        stream.append_file(relative_path, &path)?;
+        stream
+            .append_file(relative_path, &path, item.offset)
+            .context(PathContext(relative_path.into()))?;
+    }
+    Ok(())
+}
+
+/// Upper bound of a record header; real ones are a path, a few fields and maybe some xattrs
+const MAX_HEADER_LENGTH: u32 = 1024 * 1024;
+
+/// Check `path` is a plain relative path, and join it onto `dest_dir`.
+///
+/// The stream may come from the network, so it must never reach outside `dest_dir`.
+pub(crate) fn confine_path(dest_dir: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let plain = path.components().all(|x| matches!(x, Component::Normal(_)));
+    if path.as_os_str().is_empty() || !plain {
+        return Err(anyhow!("Refusing unsafe path: {}", path.display()));
+    }
+    Ok(dest_dir.join(path))
+}
+
+/// Like `fs::create_dir_all(dest_dir.join(relative))`, but refuses to go through symlinks.
+pub(crate) fn create_dirs_nofollow(dest_dir: &Path, relative: &Path) -> io::Result<()> {
+    let mut dir = dest_dir.to_path_buf();
+    for component in relative.components() {
+        dir.push(component);
+        match dir.symlink_metadata() {
+            Ok(m) if m.is_dir() => {}
+            Ok(m) if m.is_symlink() => {
+                return Err(io::Error::other(format!(
+                    "Refusing to follow symlink: {}",
+                    dir.display()
+                )));
+            }
+            Ok(_) => {
+                return Err(io::Error::other(format!(
+                    "Not a directory: {}",
+                    dir.display()
+                )));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&dir)?,
+            Err(e) => return Err(e),
+        }
     }
     Ok(())
 }
 
-pub fn receive<P, R>(mut reader: R, dest_dir: P) -> anyhow::Result<()>
+/// Where a whole file is received before replacing `path`
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".adb-sync-tmp");
+    path.with_file_name(name)
+}
+
+/// Open the host copy of a file to append to it, making it writable for the time being
+/// if it's read-only.
+///
+/// Also returns the mode to restore once done, if it was changed.
+fn open_for_append(path: &Path) -> io::Result<(File, Option<u32>)> {
+    let open = || {
+        File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+    };
+    match open() {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            let file = File::options()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(path)?;
+            let mode = file.metadata()?.permissions().mode() & 0o7777;
+            file.set_permissions(Permissions::from_mode(mode | 0o200))?;
+            match open() {
+                Ok(file) => Ok((file, Some(mode))),
+                Err(e) => {
+                    let _ = file.set_permissions(Permissions::from_mode(mode));
+                    Err(e)
+                }
+            }
+        }
+        result => Ok((result?, None)),
+    }
+}
+
+/// Receive the chunks of content from `offset` to `file_size` into `file`, which must end
+/// at `offset`, with its position there.
+///
+/// Returns `false` if the sender aborted the file.
+fn receive_content<R: Read>(
+    reader: &mut R,
+    file: &mut File,
+    mut offset: u64,
+    file_size: u64,
+    hasher: &mut Hasher,
+) -> anyhow::Result<bool> {
+    let crc = create_crc();
+    let mut buf = vec![0_u8; CHUNK_SIZE];
+    while offset < file_size {
+        let chunk_length = reader.read_u32::<LE>()?;
+        if chunk_length == ABORT_CHUNK {
+            return Ok(false);
+        }
+        if chunk_length == HOLE_CHUNK {
+            let mut length_data = [0_u8; 8];
+            reader.read_exact(&mut length_data)?;
+            let stored_checksum = reader.read_u32::<LE>()?;
+            if crc.checksum(&length_data) != stored_checksum {
+                return Err(anyhow!("Hole checksum mismatch at offset {}", offset));
+            }
+            let length = u64::from_le_bytes(length_data);
+            if length == 0 || length > file_size - offset {
+                return Err(anyhow!(
+                    "Invalid hole length {} at offset {}",
+                    length,
+                    offset
+                ));
+            }
+            // skipping over it leaves a hole, as the file ends here
+            file.seek(SeekFrom::Current(length as i64))?;
+            hash_zeros(hasher, length);
+            offset += length;
+            continue;
+        }
+        let max_length = (file_size - offset).min(CHUNK_SIZE as u64);
+        if chunk_length == 0 || chunk_length as u64 > max_length {
+            return Err(anyhow!(
+                "Invalid chunk length {} at offset {}",
+                chunk_length,
+                offset
+            ));
+        }
+
+        let chunk = &mut buf[..chunk_length as usize];
+        reader.read_exact(chunk)?;
+        let stored_checksum = reader.read_u32::<LE>()?;
+        if crc.checksum(chunk) != stored_checksum {
+            return Err(anyhow!("Chunk checksum mismatch at offset {}", offset));
+        }
+        file.write_all(chunk)?;
+        hasher.update(chunk);
+        offset += chunk_length as u64;
+    }
+    // a trailing hole was only skipped over
+    file.set_len(file_size)?;
+    Ok(true)
+}
+
+/// A regular file received and verified
+#[derive(Debug, Clone)]
+pub struct ReceivedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time on Android
+    pub mtime: SystemTime,
+    pub digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveOptions {
+    pub hash: HashAlgorithm,
+    /// Apply the permission bits of Android
+    pub perms: bool,
+    /// Apply the uid and gid of Android; needs root
+    pub owner: bool,
+    /// Rewrite the permission bits of Android, and apply them
+    pub chmod: Option<Chmod>,
+    /// Android sends xattrs
+    pub xattrs: bool,
+    /// Don't print the received paths
+    pub quiet: bool,
+}
+
+impl ReceiveOptions {
+    /// Returns the xattrs that couldn't be set.
+    fn apply_attributes(&self, file: &File, header: &Header) -> io::Result<Vec<Xattr>> {
+        if self.owner {
+            // before the others, as chown clears the setuid and setgid bits and file capabilities
+            std::os::unix::fs::fchown(file, Some(header.uid), Some(header.gid))?;
+        }
+        let unapplied = xattrs::apply(file, &header.xattrs);
+        if self.perms || self.chmod.is_some() {
+            let is_dir = matches!(header.file_type, FileType::Directory);
+            let mode = match &self.chmod {
+                Some(chmod) => chmod.apply(header.mode, is_dir),
+                None => header.mode & 0o7777,
+            };
+            file.set_permissions(Permissions::from_mode(mode))?;
+        }
+        Ok(unapplied)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReceiveSummary {
+    /// Paths of the records successfully received
+    pub received: HashSet<PathBuf>,
+    /// Files that changed on Android while being sent. Only the ones new to the host are
+    /// written; existing host copies are kept as they were.
+    pub changed: Vec<PathBuf>,
+    /// Regular files received, with their verified digests
+    pub files: Vec<ReceivedFile>,
+    /// Xattrs the host refused, of every received file when xattrs are sent
+    pub unapplied_xattrs: BTreeMap<PathBuf, Vec<Xattr>>,
+}
+
+/// Receive a send stream into `dest_dir`.
+///
+/// The summary covers the records received before a failure too, as those files are
+/// in place.
+pub fn receive<P, R>(
+    reader: R,
+    dest_dir: P,
+    options: &ReceiveOptions,
+) -> (ReceiveSummary, anyhow::Result<()>)
 where
     P: AsRef<Path>,
     R: Read,
 {
+    let mut summary = ReceiveSummary::default();
+    let result = receive_records(reader, dest_dir.as_ref(), options, &mut summary);
+    (summary, result)
+}
+
+fn receive_records<R: Read>(
+    mut reader: R,
+    dest_dir: &Path,
+    options: &ReceiveOptions,
+    summary: &mut ReceiveSummary,
+) -> anyhow::Result<()> {
+    let hash = options.hash;
+    // into `summary.files`, to look up hard link targets
+    let mut file_indices = HashMap::<PathBuf, usize>::new();
     loop {
         let mut header_length_buf = [0_u8; 4];
         let size = reader.try_read_exact(&mut header_length_buf)?;
@@ -175,6 +667,9 @@ where
             // EOF
             break;
         }
+        if header_length > MAX_HEADER_LENGTH {
+            return Err(anyhow!("Header too large: {} bytes", header_length));
+        }
 
         let mut header_buf = vec![0_u8; header_length as usize];
         reader.read_exact(&mut header_buf)?;
@@ -183,43 +678,174 @@ where
         if deser_size != header_length as usize {
             // the used data for deserialization is not the full given data
             // that's unexpected
-            panic!("Mismatched header deserialization length");
+            return Err(anyhow!("Mismatched header deserialization length"));
         }
 
         let header_path = header.path.0.as_path();
-        let dest_path = &dest_dir.as_ref().join(header_path);
-        println!("{}", dest_path.display());
+        let dest_path = &confine_path(dest_dir, header_path)?;
+        if !options.quiet {
+            println!("{}", dest_path.display());
+        }
+        let mut status = RecordStatus::Consistent;
+        // only clean up what this record created
+        let mut created = false;
+        let mut aborted = false;
+        let mut content_digest = None;
+        let mut unapplied_xattrs = Vec::new();
+        let mut linked_file = None;
+        // the length of an appended file before this record
+        let mut appended_from = None;
+        // the mode of an appended file, if it had to be made writable
+        let mut restore_mode = None;
+        // a whole file is written here, and only replaces the host copy once verified
+        let mut tmp_path = None;
+        // the record changed on Android, so the host copy was left as it was
+        let mut kept_host_copy = false;
         let send_result: anyhow::Result<()> = try {
             match header.file_type {
                 FileType::RegularFile => {
-                    if let Some(parent) = dest_path.parent() {
-                        fs::create_dir_all(parent)?;
+                    if let Some(parent) = header_path.parent() {
+                        create_dirs_nofollow(dest_dir, parent)?;
                     }
 
-                    let mut dest_file = File::options()
-                        .create(true)
-                        .truncate(true)
-                        .read(true)
-                        .write(true)
-                        .open(dest_path)?;
-                    let mut file_reader = reader.by_ref().take(header.file_size);
-
                     let crc = create_crc();
                     let mut digest = crc.digest();
                     digest.update(&header_buf);
+                    let mut hasher = hash.hasher();
 
-                    let mut crc_filter = CrcFilter::new(&mut digest, &mut dest_file);
-                    io::copy(&mut file_reader, &mut crc_filter)?;
-                    crc_filter.flush()?;
+                    // `host_copy` is the metadata of a regular file already there
+                    let (mut dest_file, host_copy) = if header.offset == 0 {
+                        let host_copy = dest_path.symlink_metadata().ok().filter(|x| x.is_file());
+                        let path = tmp_path.insert(temp_path(dest_path));
+                        match fs::remove_file(&path) {
+                            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)?,
+                            _ => {}
+                        }
+                        let file = File::options()
+                            .create_new(true)
+                            .read(true)
+                            .write(true)
+                            .custom_flags(libc::O_NOFOLLOW)
+                            .open(&path)?;
+                        (file, host_copy)
+                    } else {
+                        let (file, mode) = open_for_append(dest_path)?;
+                        restore_mode = mode;
+                        let metadata = file.metadata()?;
+                        let length = metadata.len();
+                        if length != header.offset {
+                            Err(anyhow!(
+                                "Can't append to {} from {}; it's {} bytes long",
+                                header_path.display(),
+                                header.offset,
+                                length
+                            ))?;
+                        }
+                        appended_from = Some(header.offset);
+                        io::copy(&mut (&file).take(header.offset), &mut hasher)?;
+                        (file, Some(metadata))
+                    };
+
+                    if !receive_content(
+                        &mut reader,
+                        &mut dest_file,
+                        header.offset,
+                        header.file_size,
+                        &mut hasher,
+                    )
+                    .with_context(|| format!("Broken content of {}", header_path.display()))?
+                    {
+                        aborted = true;
+                        Err(anyhow!("Aborted by Android: {}", header_path.display()))?;
+                    }
+
+                    let status_byte = reader.read_u8()?;
+                    status = RecordStatus::from_u8(status_byte)
+                        .ok_or_else(|| anyhow!("Invalid record status: {}", status_byte))?;
+                    digest.update(&[status_byte]);
+
+                    let computed_digest = hasher.finalize();
+                    let mut stored_digest = vec![0_u8; hash.digest_length()];
+                    reader.read_exact(&mut stored_digest)?;
+                    digest.update(&stored_digest);
 
                     let checksum = digest.finalize();
                     let stored_checksum = reader.read_u32::<LE>()?;
                     if checksum != stored_checksum {
                         Err(anyhow!("Checksum mismatch! {}", header_path.display()))?;
                     }
+                    if computed_digest != stored_digest {
+                        if header.offset != 0 {
+                            Err(anyhow!(
+                                "Digest mismatch! {}; the host copy isn't a prefix of it, \
+                                 sync without --append to send it whole",
+                                header_path.display()
+                            ))?;
+                        }
+                        Err(anyhow!("Digest mismatch! {}", header_path.display()))?;
+                    }
+                    if status == RecordStatus::Changed
+                        && let Some(host_copy) = &host_copy
+                    {
+                        // a consistent copy beats a possibly torn one
+                        if let Some(length) = appended_from {
+                            dest_file.set_len(length)?;
+                            filetime::set_file_handle_times(
+                                &dest_file,
+                                None,
+                                Some(FileTime::from_last_modification_time(host_copy)),
+                            )?;
+                            if let Some(mode) = restore_mode.take() {
+                                dest_file.set_permissions(Permissions::from_mode(mode))?;
+                            }
+                        }
+                        if let Some(path) = &tmp_path {
+                            fs::remove_file(path)?;
+                        }
+                        kept_host_copy = true;
+                    } else {
+                        content_digest = Some(computed_digest);
+                        if let Some(mode) = restore_mode.take() {
+                            dest_file.set_permissions(Permissions::from_mode(mode))?;
+                        }
+                        // the temp file replacing a host copy takes over its owner and mode,
+                        // unless those come from Android
+                        let replaced = host_copy.as_ref().filter(|_| tmp_path.is_some());
+                        if let Some(host_copy) = replaced
+                            && !options.owner
+                            && is_root()
+                        {
+                            std::os::unix::fs::fchown(
+                                &dest_file,
+                                Some(host_copy.uid()),
+                                Some(host_copy.gid()),
+                            )?;
+                        }
+                        unapplied_xattrs = options.apply_attributes(&dest_file, &header)?;
+                        if let Some(host_copy) = replaced
+                            && !options.perms
+                            && options.chmod.is_none()
+                        {
+                            dest_file.set_permissions(Permissions::from_mode(
+                                host_copy.mode() & 0o7777,
+                            ))?;
+                        }
+
+                        // date a changed file back to the epoch, so the next sync picks it up
+                        // again, even with `--update` that keeps host copies newer than Android's
+                        let mtime = match status {
+                            RecordStatus::Consistent => FileTime::from(header.mtime),
+                            RecordStatus::Changed => FileTime::zero(),
+                        };
+                        filetime::set_file_handle_times(&dest_file, None, Some(mtime))?;
+                        if let Some(path) = &tmp_path {
+                            fs::rename(path, dest_path)?;
+                        }
+                    }
                 }
                 FileType::Directory => {
-                    fs::create_dir_all(dest_path)?;
+                    create_dirs_nofollow(dest_dir, header_path)?;
+                    created = true;
 
                     let crc = create_crc();
                     let mut digest = crc.digest();
@@ -229,17 +855,62 @@ where
                     if checksum != stored_checksum {
                         Err(anyhow!("Checksum mismatch! {}", header_path.display()))?;
                     }
+                    unapplied_xattrs =
+                        options.apply_attributes(&File::open(dest_path)?, &header)?;
+                    filetime::set_file_mtime(dest_path, FileTime::from(header.mtime))?;
+                }
+                FileType::HardLink => {
+                    let crc = create_crc();
+                    let mut digest = crc.digest();
+                    digest.update(&header_buf);
+                    let checksum = digest.finalize();
+                    let stored_checksum = reader.read_u32::<LE>()?;
+                    if checksum != stored_checksum {
+                        Err(anyhow!("Checksum mismatch! {}", header_path.display()))?;
+                    }
+
+                    let target = header
+                        .link_target
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("Hard link without a target"))?;
+                    let target_path = target.0.as_path();
+                    // only link to a file this stream wrote, never to anything else on the host
+                    if target_path == header_path || !summary.received.contains(target_path) {
+                        Err(anyhow!(
+                            "Hard link to a file not received: {} -> {}",
+                            header_path.display(),
+                            target
+                        ))?;
+                    }
+                    if let Some(parent) = header_path.parent() {
+                        create_dirs_nofollow(dest_dir, parent)?;
+                    }
+                    match dest_path.symlink_metadata() {
+                        Ok(m) if m.is_file() => fs::remove_file(dest_path)?,
+                        Ok(_) => Err(anyhow!("Not a regular file: {}", dest_path.display()))?,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => Err(e)?,
+                    }
+                    fs::hard_link(dest_dir.join(target_path), dest_path)?;
+                    created = true;
+
+                    if let Some(&index) = file_indices.get(target_path) {
+                        let file: &ReceivedFile = &summary.files[index];
+                        linked_file = Some(ReceivedFile {
+                            path: header_path.into(),
+                            ..file.clone()
+                        });
+                    }
                 }
             }
-            filetime::set_file_mtime(dest_path, FileTime::from(header.mtime))?;
         };
         if let Err(e) = send_result {
             // delete the just-failed file/directory and exit
-            println!("Cleaning after failure...");
-            if dest_path.exists() {
+            eprintln!("Cleaning after failure...");
+            if created {
                 match header.file_type {
-                    FileType::RegularFile => {
-                        println!("Remove file: {}", dest_path.display());
+                    FileType::RegularFile | FileType::HardLink => {
+                        eprintln!("Remove file: {}", dest_path.display());
                         fs::remove_file(dest_path)?;
                     }
                     FileType::Directory => {
@@ -248,14 +919,390 @@ where
                             .map(|x| x.count() == 0)
                             .unwrap_or(false)
                         {
-                            println!("Remove dir: {}", dest_path.display());
+                            eprintln!("Remove dir: {}", dest_path.display());
                             fs::remove_dir(dest_path)?;
                         }
                     }
                 }
             }
+            if let Some(path) = &tmp_path {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)?,
+                    _ => {}
+                }
+            }
+            if let Some(length) = appended_from {
+                eprintln!("Truncate file back: {}", dest_path.display());
+                let (file, mode) = open_for_append(dest_path)?;
+                file.set_len(length)?;
+                if let Some(mode) = restore_mode.or(mode) {
+                    file.set_permissions(Permissions::from_mode(mode))?;
+                }
+            }
+            if aborted {
+                // Android reports it as skipped; the stream is still in sync
+                continue;
+            }
             Err(e)?;
         }
+        if status == RecordStatus::Changed {
+            summary.changed.push(header_path.into());
+        }
+        if options.xattrs && !kept_host_copy {
+            summary
+                .unapplied_xattrs
+                .insert(header_path.into(), unapplied_xattrs);
+        }
+        if let Some(digest) = content_digest {
+            file_indices.insert(header_path.into(), summary.files.len());
+            summary.files.push(ReceivedFile {
+                path: header_path.into(),
+                size: header.file_size,
+                mtime: header.mtime,
+                digest,
+            });
+        }
+        if let Some(file) = linked_file {
+            summary.files.push(file);
+        }
+        summary.received.insert(header_path.into());
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> ReceiveOptions {
+        ReceiveOptions {
+            quiet: true,
+            ..Default::default()
+        }
+    }
+
+    fn receive_all(buf: &[u8], dest_dir: &Path) -> anyhow::Result<ReceiveSummary> {
+        let (summary, result) = receive(buf, dest_dir, &options());
+        result.map(|()| summary)
+    }
+
+    /// A stream with the single file `a.txt`
+    fn stream_of(content: &[u8]) -> Vec<u8> {
+        append_stream_of(content, 0)
+    }
+
+    /// A stream with the single file `a.txt`, appended to the host copy from `offset`
+    fn append_stream_of(content: &[u8], offset: u64) -> Vec<u8> {
+        let src = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("a.txt"), content).unwrap();
+        let mut buf = Vec::new();
+        let mut stream = SendStream::new(&mut buf, HashAlgorithm::default(), None);
+        stream
+            .append_file(Path::new("a.txt"), &src.path().join("a.txt"), offset)
+            .unwrap();
+        assert!(stream.take_skipped().is_empty());
+        drop(stream);
+        buf
+    }
+
+    fn regular_header(path: &str, file_size: u64) -> Vec<u8> {
+        let header = Header {
+            path: path.into(),
+            file_type: FileType::RegularFile,
+            mtime: SystemTime::UNIX_EPOCH,
+            file_size,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            xattrs: Vec::new(),
+            link_target: None,
+            offset: 0,
+        };
+        encode_header(header)
+    }
+
+    fn encode_header(header: Header) -> Vec<u8> {
+        let header_data = bincode::encode_to_vec(header, bincode_config()).unwrap();
+        let mut buf = Vec::new();
+        buf.write_u32::<LE>(header_data.len() as u32).unwrap();
+        buf.extend_from_slice(&header_data);
+        buf
+    }
+
+    /// The record `append_file` would write for `content`, with `status`
+    fn regular_record(path: &str, content: &[u8], status: RecordStatus) -> Vec<u8> {
+        let mut buf = regular_header(path, content.len() as u64);
+        let crc = create_crc();
+        let mut digest = crc.digest();
+        digest.update(&buf[4..]);
+        let mut hasher = HashAlgorithm::default().hasher();
+        for chunk in content.chunks(CHUNK_SIZE) {
+            buf.write_u32::<LE>(chunk.len() as u32).unwrap();
+            buf.extend_from_slice(chunk);
+            buf.write_u32::<LE>(crc.checksum(chunk)).unwrap();
+            hasher.update(chunk);
+        }
+        buf.push(status as u8);
+        digest.update(&[status as u8]);
+        let content_digest = hasher.finalize();
+        buf.extend_from_slice(&content_digest);
+        digest.update(&content_digest);
+        buf.write_u32::<LE>(digest.finalize()).unwrap();
+        buf
+    }
+
+    fn with_eof(mut buf: Vec<u8>) -> Vec<u8> {
+        buf.write_u32::<LE>(0xFFFFFFFF).unwrap();
+        buf
+    }
+
+    #[test]
+    fn confine_path_joins_plain_paths() {
+        let dest = Path::new("/dest");
+        assert_eq!(
+            confine_path(dest, Path::new("a/b.txt")).unwrap(),
+            Path::new("/dest/a/b.txt")
+        );
+    }
+
+    #[test]
+    fn confine_path_refuses_escapes() {
+        let dest = Path::new("/dest");
+        for path in ["", "/etc/passwd", "..", "../a", "a/../../b", "./a"] {
+            assert!(confine_path(dest, Path::new(path)).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn receive_round_trip() {
+        let dest = tempfile::tempdir().unwrap();
+        let summary = receive_all(&stream_of(b"hello"), dest.path()).unwrap();
+        assert_eq!(summary.files.len(), 1);
+        assert_eq!(fs::read(dest.path().join("a.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn receive_refuses_missing_eof_mark() {
+        let dest = tempfile::tempdir().unwrap();
+        assert!(receive_all(&[][..], dest.path()).is_err());
+        assert!(receive_all(&[0xFF, 0xFF][..], dest.path()).is_err());
+    }
+
+    #[test]
+    fn receive_refuses_oversized_header() {
+        let dest = tempfile::tempdir().unwrap();
+        let mut buf = Vec::new();
+        buf.write_u32::<LE>(MAX_HEADER_LENGTH + 1).unwrap();
+        let e = receive_all(buf.as_slice(), dest.path()).unwrap_err();
+        assert!(e.to_string().contains("Header too large"), "{}", e);
+    }
+
+    #[test]
+    fn receive_removes_truncated_file() {
+        let dest = tempfile::tempdir().unwrap();
+        let buf = stream_of(&[7_u8; 1000]);
+        for length in [8, 100, buf.len() - 8] {
+            assert!(receive_all(&buf[..length], dest.path()).is_err());
+            assert!(!dest.path().join("a.txt").exists(), "{}", length);
+        }
+    }
+
+    #[test]
+    fn receive_refuses_invalid_chunk_length() {
+        let dest = tempfile::tempdir().unwrap();
+        for chunk_length in [0, 5, CHUNK_SIZE as u32 + 1] {
+            let mut buf = regular_header("a.txt", 4);
+            buf.write_u32::<LE>(chunk_length).unwrap();
+            buf.extend_from_slice(&[0_u8; 8]);
+            let e = receive_all(buf.as_slice(), dest.path()).unwrap_err();
+            assert!(
+                format!("{:#}", e).contains("Invalid chunk length"),
+                "{:#}",
+                e
+            );
+            assert!(!dest.path().join("a.txt").exists());
+        }
+    }
+
+    #[test]
+    fn receive_refuses_unsafe_path() {
+        let dest = tempfile::tempdir().unwrap();
+        let mut buf = regular_header("../a.txt", 0);
+        buf.write_u32::<LE>(0xFFFFFFFF).unwrap();
+        assert!(receive_all(buf.as_slice(), dest.path()).is_err());
+    }
+
+    #[test]
+    fn changed_record_keeps_host_copy() {
+        let dest = tempfile::tempdir().unwrap();
+        fs::write(dest.path().join("a.txt"), b"old").unwrap();
+        let mut buf = regular_record("a.txt", b"torn", RecordStatus::Changed);
+        buf.extend(regular_record("b.txt", b"torn", RecordStatus::Changed));
+        let summary = receive_all(with_eof(buf).as_slice(), dest.path()).unwrap();
+        assert_eq!(fs::read(dest.path().join("a.txt")).unwrap(), b"old");
+        // nothing better to keep
+        assert_eq!(fs::read(dest.path().join("b.txt")).unwrap(), b"torn");
+        assert_eq!(summary.changed.len(), 2);
+        assert_eq!(summary.files.len(), 1);
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn replaced_host_copy_keeps_its_mode() {
+        let dest = tempfile::tempdir().unwrap();
+        let path = dest.path().join("a.txt");
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o640)).unwrap();
+        let is_root = is_root();
+        if is_root {
+            std::os::unix::fs::chown(&path, Some(65534), Some(65534)).unwrap();
+        }
+        let buf = regular_record("a.txt", b"new", RecordStatus::Consistent);
+        receive_all(with_eof(buf).as_slice(), dest.path()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o640);
+        if is_root {
+            assert_eq!((metadata.uid(), metadata.gid()), (65534, 65534));
+        }
+
+        // unless Android's mode is asked for
+        let options = ReceiveOptions {
+            perms: true,
+            ..options()
+        };
+        let buf = regular_record("a.txt", b"newer", RecordStatus::Consistent);
+        let (_, result) = receive(with_eof(buf).as_slice(), dest.path(), &options);
+        result.unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o7777, 0o644);
+    }
+
+    #[test]
+    fn hard_link_round_trip() {
+        let src = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("a.txt"), b"shared").unwrap();
+        fs::hard_link(src.path().join("a.txt"), src.path().join("b.txt")).unwrap();
+        let mut buf = Vec::new();
+        let mut stream = SendStream::new(&mut buf, HashAlgorithm::default(), None);
+        for name in ["a.txt", "b.txt"] {
+            stream
+                .append_file(Path::new(name), &src.path().join(name), 0)
+                .unwrap();
+        }
+        drop(stream);
+
+        let dest = tempfile::tempdir().unwrap();
+        let summary = receive_all(&buf, dest.path()).unwrap();
+        let a = fs::metadata(dest.path().join("a.txt")).unwrap();
+        let b = fs::metadata(dest.path().join("b.txt")).unwrap();
+        assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+        assert_eq!(fs::read(dest.path().join("b.txt")).unwrap(), b"shared");
+        // both paths are recorded, with the same digest
+        assert_eq!(summary.files.len(), 2);
+        assert_eq!(summary.files[0].digest, summary.files[1].digest);
+    }
+
+    #[test]
+    fn hard_link_to_file_not_received_is_refused() {
+        let dest = tempfile::tempdir().unwrap();
+        fs::write(dest.path().join("secret.txt"), b"host only").unwrap();
+        let mut buf = encode_header(Header {
+            path: "b.txt".into(),
+            file_type: FileType::HardLink,
+            mtime: SystemTime::UNIX_EPOCH,
+            file_size: 0,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            xattrs: Vec::new(),
+            link_target: Some("secret.txt".into()),
+            offset: 0,
+        });
+        let crc = create_crc();
+        let checksum = crc.checksum(&buf[4..]);
+        buf.write_u32::<LE>(checksum).unwrap();
+        let e = receive_all(with_eof(buf).as_slice(), dest.path()).unwrap_err();
+        assert!(
+            e.to_string().contains("Hard link to a file not received"),
+            "{}",
+            e
+        );
+        assert!(!dest.path().join("b.txt").exists());
+        assert_eq!(
+            fs::metadata(dest.path().join("secret.txt"))
+                .unwrap()
+                .nlink(),
+            1
+        );
+    }
+
+    #[test]
+    fn sparse_file_round_trip() {
+        let src = tempfile::tempdir().unwrap();
+        let path = src.path().join("a.txt");
+        let file = File::create(&path).unwrap();
+        file.set_len(64 * CHUNK_SIZE as u64).unwrap();
+        (&file)
+            .seek(SeekFrom::Start(32 * CHUNK_SIZE as u64))
+            .unwrap();
+        (&file).write_all(b"data in the middle").unwrap();
+        drop(file);
+        let mut buf = Vec::new();
+        let mut stream = SendStream::new(&mut buf, HashAlgorithm::default(), None);
+        stream.append_file(Path::new("a.txt"), &path, 0).unwrap();
+        drop(stream);
+        // only the data region goes over the wire
+        assert!(buf.len() < 2 * CHUNK_SIZE, "{}", buf.len());
+
+        let dest = tempfile::tempdir().unwrap();
+        receive_all(&buf, dest.path()).unwrap();
+        assert_eq!(
+            fs::read(dest.path().join("a.txt")).unwrap(),
+            fs::read(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn receive_refuses_hole_past_the_end() {
+        let dest = tempfile::tempdir().unwrap();
+        let mut buf = regular_header("a.txt", 100);
+        let length_data = 101_u64.to_le_bytes();
+        buf.write_u32::<LE>(HOLE_CHUNK).unwrap();
+        buf.extend_from_slice(&length_data);
+        buf.write_u32::<LE>(create_crc().checksum(&length_data))
+            .unwrap();
+        let e = receive_all(buf.as_slice(), dest.path()).unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("Invalid hole length"),
+            "{:#}",
+            e
+        );
+        assert!(!dest.path().join("a.txt").exists());
+    }
+
+    #[test]
+    fn append_round_trip() {
+        let dest = tempfile::tempdir().unwrap();
+        fs::write(dest.path().join("a.txt"), b"hello").unwrap();
+        receive_all(&append_stream_of(b"hello world", 5), dest.path()).unwrap();
+        assert_eq!(fs::read(dest.path().join("a.txt")).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn append_refuses_offset_off_the_host_length() {
+        let dest = tempfile::tempdir().unwrap();
+        fs::write(dest.path().join("a.txt"), b"hell").unwrap();
+        let e = receive_all(&append_stream_of(b"hello world", 5), dest.path()).unwrap_err();
+        assert!(e.to_string().contains("Can't append"), "{}", e);
+        assert_eq!(fs::read(dest.path().join("a.txt")).unwrap(), b"hell");
+    }
+
+    #[test]
+    fn append_digest_mismatch_truncates_back() {
+        let dest = tempfile::tempdir().unwrap();
+        fs::write(dest.path().join("a.txt"), b"HELLO").unwrap();
+        let e = receive_all(&append_stream_of(b"hello world", 5), dest.path()).unwrap_err();
+        assert!(e.to_string().contains("Digest mismatch"), "{}", e);
+        assert_eq!(fs::read(dest.path().join("a.txt")).unwrap(), b"HELLO");
+    }
+}
@@ -2,17 +2,66 @@ use std::io::{Read, Write};
 
 use anyhow::anyhow;
 
+use crate::hash::{hash_file, hash_file_range};
+use crate::index_dir;
 use crate::send_stream::{SendStream, write_send_list_to_stream};
-use crate::stream::protocol::{MAGIC, Message, SendConfig};
+use crate::stream::auth::{AuthToken, challenge};
+use crate::stream::protocol::{Capabilities, Hello, MAGIC, Message, SendConfig};
 use crate::stream::{ReadBincode, WriteBincode};
-use crate::{Entry, index_dir};
 
-pub fn handle_connection<S: Read + Write>(mut stream: S) -> anyhow::Result<()> {
+/// Serve one host connection.
+///
+/// This is [`handshake`] followed by [`serve_connection`].
+pub fn handle_connection<S: Read + Write>(
+    mut stream: S,
+    token: Option<&AuthToken>,
+) -> anyhow::Result<()> {
+    let capabilities = handshake(&mut stream, token)?;
+    serve_connection(stream, capabilities)
+}
+
+/// Check `MAGIC` and exchange a [`Hello`] with the host. When `token` is given, the host
+/// then has to answer a challenge before any [`Message`] is accepted.
+///
+/// Until this returns, the peer isn't trusted to be the host.
+pub fn handshake<S: Read + Write>(
+    stream: &mut S,
+    token: Option<&AuthToken>,
+) -> anyhow::Result<Capabilities> {
     let mut magic_buf = [0_u8; MAGIC.len()];
     stream.read_exact(&mut magic_buf)?;
     if &magic_buf != MAGIC {
         return Err(anyhow!("Invalid magic: {:?}", magic_buf));
     }
+
+    let host_hello: Hello = stream.read_bincode()?;
+    let hello = Hello::new();
+    stream.write_bincode(hello)?;
+    let capabilities = Hello::negotiate(host_hello, hello)?;
+
+    if let Some(token) = token {
+        challenge(stream, token)?;
+        stream.write_bincode(&Message::Ok)?;
+    }
+    Ok(capabilities)
+}
+
+/// Serve the messages of a host that passed the [`handshake`].
+///
+/// A failure is reported to the host as `Message::Error` before returning it.
+pub fn serve_connection<S: Read + Write>(
+    mut stream: S,
+    capabilities: Capabilities,
+) -> anyhow::Result<()> {
+    let result = serve(&mut stream, capabilities);
+    if let Err(e) = &result {
+        let _ = stream.write_bincode(Message::error(e));
+        let _ = stream.flush();
+    }
+    result
+}
+
+fn serve<S: Read + Write>(mut stream: S, capabilities: Capabilities) -> anyhow::Result<()> {
     macro_rules! send_ok {
         () => {
             stream.write_bincode(&Message::Ok)?;
@@ -27,24 +76,65 @@ pub fn handle_connection<S: Read + Write>(mut stream: S) -> anyhow::Result<()> {
             break;
         }
     }
-    send_ok!();
-
-    let entries = index_dir(&send_config.path, send_config.skip_failed)?;
-    stream.write_bincode(&entries)?;
-    send_ok!();
-
-    let send_list: Vec<Entry> = stream.read_bincode()?;
-    send_ok!();
+    if !capabilities.contains(send_config.hash.capability()) {
+        return Err(anyhow!("Unsupported hash: {}", send_config.hash));
+    }
 
-    let mut send_stream = SendStream::new(&mut stream);
-    write_send_list_to_stream(
-        &mut send_stream,
+    let index = index_dir(
         &send_config.path,
-        send_list.into_iter().map(|x| x.path),
-        |_, _| {},
+        send_config.skip_failed,
+        &send_config.filter,
     )?;
-    drop(send_stream);
     send_ok!();
+    stream.write_bincode(&index)?;
+    send_ok!();
+
+    loop {
+        match stream.read_bincode::<Message>()? {
+            Message::SendList(send_list) => {
+                send_ok!();
+                let mut send_stream =
+                    SendStream::new(&mut stream, send_config.hash, send_config.xattrs.clone());
+                write_send_list_to_stream(
+                    &mut send_stream,
+                    &send_config.path,
+                    send_list.into_iter(),
+                    |_, _| {},
+                )?;
+                let skipped = send_stream.take_skipped();
+                drop(send_stream);
+                stream.write_bincode(Message::Skipped(skipped))?;
+                send_ok!();
+            }
+            Message::HashFiles(paths) => {
+                let digests = paths
+                    .iter()
+                    .map(|x| {
+                        hash_file(send_config.path.join(&x.0), send_config.hash)
+                            .map_err(|e| e.to_string())
+                    })
+                    .collect();
+                stream.write_bincode(Message::Digests(digests))?;
+            }
+            Message::HashRanges(ranges) => {
+                let digests = ranges
+                    .iter()
+                    .map(|x| {
+                        hash_file_range(
+                            send_config.path.join(&x.path.0),
+                            send_config.hash,
+                            x.start,
+                            x.end,
+                        )
+                        .map_err(|e| e.to_string())
+                    })
+                    .collect();
+                stream.write_bincode(Message::Digests(digests))?;
+            }
+            Message::Finish => break,
+            message => return Err(anyhow!("Unexpected message: {:?}", message)),
+        }
+    }
 
     Ok(())
 }
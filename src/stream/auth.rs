@@ -0,0 +1,90 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const TOKEN_LENGTH: usize = 32;
+pub const CHALLENGE_LENGTH: usize = 32;
+const PROOF_LENGTH: usize = 32;
+
+/// Per-session secret
+///
+/// The host generates it and hands it to `tcp-server` through the adb shell
+/// command line, which is the only channel both sides already trust.
+#[derive(Clone)]
+pub struct AuthToken([u8; TOKEN_LENGTH]);
+
+impl AuthToken {
+    pub fn generate() -> anyhow::Result<Self> {
+        let mut token = [0_u8; TOKEN_LENGTH];
+        getrandom::getrandom(&mut token).map_err(|e| anyhow!("Failed to generate token: {}", e))?;
+        Ok(Self(token))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn from_hex(hex: &str) -> anyhow::Result<Self> {
+        let mut token = [0_u8; TOKEN_LENGTH];
+        hex::decode_to_slice(hex, &mut token).map_err(|e| anyhow!("Invalid token: {}", e))?;
+        Ok(Self(token))
+    }
+
+    fn mac(&self, challenge: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC accepts any key size");
+        mac.update(challenge);
+        mac
+    }
+
+    /// Derive a sub-key for `label` and `salt`, so the token itself is never used as a
+    /// cipher key.
+    pub fn derive_key(&self, label: &[u8], salt: &[u8]) -> [u8; 32] {
+        let mut mac = self.mac(label);
+        mac.update(salt);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+/// The peer failed to prove it knows the session token
+#[derive(Debug)]
+pub struct AuthError;
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Authentication failed")
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Android side: send a random challenge and verify the host's HMAC over it.
+pub fn challenge<S: Read + Write>(stream: &mut S, token: &AuthToken) -> anyhow::Result<()> {
+    let mut challenge = [0_u8; CHALLENGE_LENGTH];
+    getrandom::getrandom(&mut challenge)
+        .map_err(|e| anyhow!("Failed to generate challenge: {}", e))?;
+    stream.write_all(&challenge)?;
+    stream.flush()?;
+
+    let mut proof = [0_u8; PROOF_LENGTH];
+    stream.read_exact(&mut proof)?;
+    token
+        .mac(&challenge)
+        .verify_slice(&proof)
+        .map_err(|_| AuthError)?;
+    Ok(())
+}
+
+/// Host side: answer the challenge sent by [`challenge`].
+pub fn respond<S: Read + Write>(stream: &mut S, token: &AuthToken) -> anyhow::Result<()> {
+    let mut challenge = [0_u8; CHALLENGE_LENGTH];
+    stream.read_exact(&mut challenge)?;
+    let proof = token.mac(&challenge).finalize().into_bytes();
+    stream.write_all(&proof)?;
+    stream.flush()?;
+    Ok(())
+}
@@ -0,0 +1,301 @@
+use std::io;
+use std::io::{Read, Write};
+
+use byteorder::{ByteOrder, LE, WriteBytesExt};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::TryReadExact;
+use crate::stream::auth::{AuthError, AuthToken};
+
+/// Max plaintext size of one frame
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const SALT_LENGTH: usize = 32;
+
+const HOST_TO_ANDROID_LABEL: &[u8] = b"adb-sync host->android";
+const ANDROID_TO_HOST_LABEL: &[u8] = b"adb-sync android->host";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    Host,
+    Android,
+}
+
+/// ChaCha20-Poly1305 framing layer
+///
+/// - Stream structure:
+///   \[ Salt (32 bytes, plain) | Frame... \]
+/// - Frame structure:
+///   \[ CiphertextLength (u32) | Ciphertext | Tag \]
+///
+/// Each direction has its own key derived from the session token and the random salts
+/// of both sides, and the nonce is a frame counter, so frames can't be replayed,
+/// reordered or reflected, even across connections sharing the token.
+///
+/// Writes are buffered into frames. Pending writes are flushed before each blocking read,
+/// which is what a request-response protocol needs, and on drop.
+pub struct EncryptedStream<S: Read + Write> {
+    inner: S,
+    send_cipher: ChaCha20Poly1305,
+    recv_cipher: ChaCha20Poly1305,
+    send_counter: u64,
+    recv_counter: u64,
+    write_buf: Vec<u8>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<S: Read + Write> EncryptedStream<S> {
+    /// Exchange the salts with the peer and set up the ciphers.
+    pub fn new(mut inner: S, token: &AuthToken, role: Role) -> io::Result<Self> {
+        let mut own_salt = [0_u8; SALT_LENGTH];
+        getrandom::getrandom(&mut own_salt)
+            .map_err(|e| io::Error::other(format!("Failed to generate salt: {}", e)))?;
+        inner.write_all(&own_salt)?;
+        inner.flush()?;
+        let mut peer_salt = [0_u8; SALT_LENGTH];
+        inner.read_exact(&mut peer_salt)?;
+
+        let (host_salt, android_salt) = match role {
+            Role::Host => (own_salt, peer_salt),
+            Role::Android => (peer_salt, own_salt),
+        };
+        let salt = [host_salt, android_salt].concat();
+        let host_to_android = token.derive_key(HOST_TO_ANDROID_LABEL, &salt);
+        let android_to_host = token.derive_key(ANDROID_TO_HOST_LABEL, &salt);
+        let (send_key, recv_key) = match role {
+            Role::Host => (host_to_android, android_to_host),
+            Role::Android => (android_to_host, host_to_android),
+        };
+        Ok(Self {
+            inner,
+            send_cipher: ChaCha20Poly1305::new(Key::from_slice(&send_key)),
+            recv_cipher: ChaCha20Poly1305::new(Key::from_slice(&recv_key)),
+            send_counter: 0,
+            recv_counter: 0,
+            write_buf: Vec::new(),
+            read_buf: Vec::new(),
+            read_pos: 0,
+        })
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0_u8; 12];
+        LE::write_u64(&mut nonce[4..], counter);
+        nonce.into()
+    }
+
+    fn write_frame(&mut self, size: usize) -> io::Result<()> {
+        let nonce = Self::nonce(self.send_counter);
+        self.send_counter += 1;
+        let ciphertext = self
+            .send_cipher
+            .encrypt(&nonce, &self.write_buf[..size])
+            .map_err(|_| io::Error::other("Encryption failure"))?;
+        self.write_buf.drain(..size);
+        self.inner.write_u32::<LE>(ciphertext.len() as u32)?;
+        self.inner.write_all(&ciphertext)
+    }
+
+    fn flush_frames(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            self.write_frame(self.write_buf.len().min(MAX_FRAME_SIZE))?;
+        }
+        self.inner.flush()
+    }
+
+    /// Returns `false` on a clean EOF.
+    fn read_frame(&mut self) -> io::Result<bool> {
+        let mut length_buf = [0_u8; 4];
+        match self.inner.try_read_exact(&mut length_buf)? {
+            0 => return Ok(false),
+            4 => {}
+            _ => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+        let length = LE::read_u32(&length_buf) as usize;
+        if !(TAG_SIZE..=MAX_FRAME_SIZE + TAG_SIZE).contains(&length) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, AuthError));
+        }
+        let mut ciphertext = vec![0_u8; length];
+        self.inner.read_exact(&mut ciphertext)?;
+
+        let nonce = Self::nonce(self.recv_counter);
+        self.recv_counter += 1;
+        self.read_buf = self
+            .recv_cipher
+            .decrypt(&nonce, ciphertext.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, AuthError))?;
+        self.read_pos = 0;
+        Ok(true)
+    }
+}
+
+impl<S: Read + Write> Read for EncryptedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_pos == self.read_buf.len() {
+            // the peer may be waiting for what we've written before answering
+            self.flush_frames()?;
+            if !self.read_frame()? {
+                return Ok(0);
+            }
+        }
+        let available = &self.read_buf[self.read_pos..];
+        let size = available.len().min(buf.len());
+        buf[..size].copy_from_slice(&available[..size]);
+        self.read_pos += size;
+        Ok(size)
+    }
+}
+
+impl<S: Read + Write> Write for EncryptedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buf.extend_from_slice(buf);
+        while self.write_buf.len() >= MAX_FRAME_SIZE {
+            self.write_frame(MAX_FRAME_SIZE)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_frames()
+    }
+}
+
+impl<S: Read + Write> Drop for EncryptedStream<S> {
+    fn drop(&mut self) {
+        let _ = self.flush_frames();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::thread::spawn;
+
+    use super::*;
+
+    fn is_auth_error(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|x| x.is::<AuthError>())
+    }
+
+    fn read_frame_data(stream: &mut UnixStream) -> Vec<u8> {
+        let mut length = [0_u8; 4];
+        stream.read_exact(&mut length).unwrap();
+        let mut frame = length.to_vec();
+        frame.resize(4 + LE::read_u32(&length) as usize, 0);
+        stream.read_exact(&mut frame[4..]).unwrap();
+        frame
+    }
+
+    /// Send `b"one!"` and `b"two!"` in two frames from the host to Android, with `tamper`
+    /// rewriting the frames in between, and return whether Android reads both.
+    fn relay<F>(host_token: &AuthToken, android_token: &AuthToken, tamper: F) -> io::Result<()>
+    where
+        F: FnOnce(Vec<Vec<u8>>) -> Vec<Vec<u8>>,
+    {
+        let (host_end, mut from_host) = UnixStream::pair().unwrap();
+        let (mut to_android, android_end) = UnixStream::pair().unwrap();
+        let android_token = android_token.clone();
+        let android = spawn(move || -> io::Result<()> {
+            let mut stream = EncryptedStream::new(android_end, &android_token, Role::Android)?;
+            for expected in [b"one!", b"two!"] {
+                let mut buf = [0_u8; 4];
+                stream.read_exact(&mut buf)?;
+                assert_eq!(&buf, expected);
+            }
+            Ok(())
+        });
+
+        let mut salt = [0_u8; SALT_LENGTH];
+        to_android.read_exact(&mut salt).unwrap();
+        from_host.write_all(&salt).unwrap();
+        let mut host = EncryptedStream::new(host_end, host_token, Role::Host).unwrap();
+        from_host.read_exact(&mut salt).unwrap();
+        to_android.write_all(&salt).unwrap();
+
+        host.write_all(b"one!").unwrap();
+        host.flush().unwrap();
+        host.write_all(b"two!").unwrap();
+        host.flush().unwrap();
+        let frames = vec![
+            read_frame_data(&mut from_host),
+            read_frame_data(&mut from_host),
+        ];
+        for frame in tamper(frames) {
+            // Android hangs up on the first bad frame
+            let _ = to_android.write_all(&frame);
+        }
+        drop(to_android);
+        android.join().unwrap()
+    }
+
+    #[test]
+    fn round_trip_interleaved() {
+        let token = AuthToken::generate().unwrap();
+        let (host_end, android_end) = UnixStream::pair().unwrap();
+        let android_token = token.clone();
+        let android = spawn(move || {
+            let mut stream =
+                EncryptedStream::new(android_end, &android_token, Role::Android).unwrap();
+            let mut buf = vec![0_u8; 3 * MAX_FRAME_SIZE + 1];
+            for _ in 0..2 {
+                stream.read_exact(&mut buf).unwrap();
+                buf.reverse();
+                stream.write_all(&buf).unwrap();
+            }
+        });
+
+        let mut host = EncryptedStream::new(host_end, &token, Role::Host).unwrap();
+        for seed in [1_u8, 2] {
+            let data = (0..3 * MAX_FRAME_SIZE + 1)
+                .map(|x| (x % 251) as u8 ^ seed)
+                .collect::<Vec<_>>();
+            host.write_all(&data).unwrap();
+            let mut echoed = vec![0_u8; data.len()];
+            host.read_exact(&mut echoed).unwrap();
+            echoed.reverse();
+            assert_eq!(echoed, data);
+        }
+        android.join().unwrap();
+    }
+
+    #[test]
+    fn relay_passes_untouched_frames() {
+        let token = AuthToken::generate().unwrap();
+        assert!(relay(&token, &token, |x| x).is_ok());
+    }
+
+    #[test]
+    fn wrong_token_is_rejected() {
+        let token = AuthToken::generate().unwrap();
+        let other = AuthToken::generate().unwrap();
+        let e = relay(&token, &other, |x| x).unwrap_err();
+        assert!(is_auth_error(&e), "{}", e);
+    }
+
+    #[test]
+    fn flipped_byte_is_rejected() {
+        let token = AuthToken::generate().unwrap();
+        let e = relay(&token, &token, |mut x| {
+            x[0][6] ^= 1;
+            x
+        })
+        .unwrap_err();
+        assert!(is_auth_error(&e), "{}", e);
+    }
+
+    #[test]
+    fn replayed_frame_is_rejected() {
+        let token = AuthToken::generate().unwrap();
+        let e = relay(&token, &token, |x| vec![x[0].clone(), x[0].clone()]).unwrap_err();
+        assert!(is_auth_error(&e), "{}", e);
+    }
+
+    #[test]
+    fn reordered_frames_are_rejected() {
+        let token = AuthToken::generate().unwrap();
+        let e = relay(&token, &token, |x| vec![x[1].clone(), x[0].clone()]).unwrap_err();
+        assert!(is_auth_error(&e), "{}", e);
+    }
+}
@@ -1,68 +1,681 @@
-use std::io::Read;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use bytesize::ByteSize;
 use colored::Colorize;
-use log::info;
+use log::{debug, info, warn};
+use serde::Serialize;
 
-use crate::send_stream::receive;
-use crate::stream::protocol::{MAGIC, Message, SendConfig};
+use crate::hash::{HashAlgorithm, hash_file, hash_file_range};
+use crate::manifest;
+use crate::renames;
+use crate::renames::{Matcher, Rename};
+use crate::send_stream::{ReceiveOptions, ReceiveSummary, ReceivedFile, receive};
+use crate::stream::auth::{AuthToken, respond};
+use crate::stream::protocol::{
+    HashRange, Hello, MAGIC, Message, RemoteError, SendConfig, SendItem,
+};
 use crate::stream::{ReadBincode, WriteBincode};
-use crate::{Entry, generate_send_list};
+use crate::unix_path::UnixPath;
+use crate::xattrs;
+use crate::{
+    CONFIG, DEFAULT_MODIFY_WINDOW, Entry, Index, IndexFilter, SendDecision, Skipped,
+    detect_mtime_granularity, generate_send_list, index_dir, mutex_lock,
+};
 
+/// What `--json` prints at the end of a sync
+#[derive(Serialize, Default)]
+struct SyncSummary {
+    dry_run: bool,
+    /// Files indexed on Android
+    entries: usize,
+    /// Files excluded by the filters, size, age and rules
+    filtered: usize,
+    /// Directories excluded whole by the rules, their files not counted in `filtered`
+    pruned_dirs: usize,
+    skipped_by_policy: usize,
+    /// Found on the host under other paths
+    renamed: usize,
+    send_list: usize,
+    send_bytes: u64,
+    not_received: usize,
+    /// Left out by Android while indexing
+    skipped: usize,
+}
+
+/// How much of the end of a host copy `--append-verify` compares
+const APPEND_VERIFY_LENGTH: u64 = 1024 * 1024;
+
+/// Read a [`Message`], turning `Message::Error` into a [`RemoteError`].
+fn read_message<R: Read>(stream: &mut R) -> anyhow::Result<Message> {
+    match stream.read_bincode::<Message>()? {
+        Message::Error {
+            kind,
+            message,
+            path,
+        } => Err(RemoteError {
+            kind,
+            message,
+            path,
+        }
+        .into()),
+        message => Ok(message),
+    }
+}
+
+macro_rules! check_ok {
+    ($stream:expr) => {
+        if (read_message($stream)? != Message::Ok) {
+            return Err(anyhow!("Android is not OK!"));
+        }
+    };
+}
+
+/// Sync `send_config.path` on Android into `dest_dir`.
 pub fn start<S: Read + Write>(
     mut stream: S,
     send_config: SendConfig,
     dest_dir: &Path,
+    token: Option<&AuthToken>,
+) -> anyhow::Result<()> {
+    let hash = send_config.hash;
+    let filter = send_config.filter.clone();
+    let index = open_session(&mut stream, send_config, token)?;
+
+    info!("{}", "Generating send list...".cyan().bold());
+    let config = mutex_lock!(CONFIG).clone().unwrap();
+    let mut summary = SyncSummary {
+        dry_run: config.dry_run,
+        entries: index.entries.len(),
+        filtered: index.filtered,
+        pruned_dirs: index.pruned_dirs,
+        skipped: index.skipped.len(),
+        ..Default::default()
+    };
+    let modify_window = match config.modify_window {
+        Some(x) => x,
+        None => match detect_mtime_granularity(dest_dir) {
+            Ok(x) => {
+                if !x.is_zero() {
+                    info!(
+                        "Destination keeps mtimes within {:?}; using it as --modify-window",
+                        x
+                    );
+                }
+                x
+            }
+            Err(e) => {
+                warn!(
+                    "Can't detect the mtime granularity of the destination: {}; using {:?}",
+                    e, DEFAULT_MODIFY_WINDOW
+                );
+                DEFAULT_MODIFY_WINDOW
+            }
+        },
+    };
+    let mut decisions = generate_send_list(index.entries, dest_dir, modify_window)?;
+    let renames = if config.detect_renames && dest_dir.exists() {
+        info!("{}", "Detecting renames...".cyan().bold());
+        let android_paths = decisions
+            .iter()
+            .map(|(x, _)| x.path.0.as_path())
+            .collect::<HashSet<_>>();
+        // what Android left out, it may still have; those host files aren't gone
+        let orphans = renames::orphans(
+            index_dir(dest_dir, true, &filter)?.entries,
+            &android_paths,
+            &index.skipped,
+        );
+        detect_renames(
+            &mut stream,
+            &mut decisions,
+            orphans,
+            dest_dir,
+            hash,
+            modify_window,
+            config.verify_renames,
+        )?
+    } else {
+        Vec::new()
+    };
+    if config.append_verify {
+        verify_appends(&mut stream, &mut decisions, dest_dir, hash)?;
+    }
+    if config.verbose || config.dry_run {
+        for (entry, decision) in &decisions {
+            if *decision == SendDecision::UpToDate {
+                debug!("skip {} ({})", entry.path, decision);
+            } else {
+                let action = if decision.sends() { "send" } else { "skip" };
+                info!("{} {} ({})", action, entry.path, decision);
+            }
+        }
+    }
+    summary.skipped_by_policy = decisions.iter().filter(|(_, x)| x.by_policy()).count();
+    if summary.skipped_by_policy != 0 {
+        info!(
+            "Skipped by --update/--ignore-existing/--existing: {}",
+            summary.skipped_by_policy
+        );
+    }
+    let mut found_on_host = HashMap::new();
+    let mut send_list = Vec::new();
+    let mut send_size = 0;
+    for (entry, decision) in decisions {
+        match decision {
+            SendDecision::FoundOnHost => {
+                found_on_host.insert(entry.path.0.clone(), entry);
+            }
+            SendDecision::Append { offset } => {
+                send_size += entry.size - offset;
+                send_list.push(SendItem {
+                    path: entry.path,
+                    offset,
+                });
+            }
+            x if x.sends() => {
+                send_size += entry.size;
+                send_list.push(SendItem::whole(&entry));
+            }
+            _ => {}
+        }
+    }
+    if !renames.is_empty() {
+        info!("Found on the host under other paths: {}", renames.len());
+        for x in &renames {
+            let copied = if x.copy { " (copied)" } else { "" };
+            info!("  {} -> {}{}", x.from.display(), x.to.display(), copied);
+        }
+    }
+    info!(
+        "{}",
+        format!(
+            "Send list: {}, {}",
+            send_list.len(),
+            ByteSize(send_size).to_string_as(true)
+        )
+        .cyan()
+        .bold()
+    );
+    summary.renamed = renames.len();
+    summary.send_list = send_list.len();
+    summary.send_bytes = send_size;
+    if config.dry_run {
+        stream.write_bincode(Message::Finish)?;
+        report_skipped("Skipped while indexing", &index.skipped);
+        info!("{}", "Dry run; nothing transferred".cyan().bold());
+        if config.json {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        }
+        return Ok(());
+    }
+    let mut renamed = Vec::new();
+    for x in renames {
+        match renames::apply(dest_dir, &x) {
+            Ok(()) => renamed.push(x),
+            Err(e) => {
+                warn!(
+                    "Can't move {} to {}: {}",
+                    x.from.display(),
+                    x.to.display(),
+                    e
+                );
+                send_list.push(SendItem::whole(&found_on_host[&x.to]));
+            }
+        }
+    }
+    let missing = transfer(&mut stream, &send_list, dest_dir, hash, &renamed)?;
+    stream.write_bincode(Message::Finish)?;
+
+    report_skipped("Skipped while indexing", &index.skipped);
+    if config.json {
+        summary.renamed = renamed.len();
+        summary.not_received = missing.len();
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    }
+    check_received(&missing, send_list.len())?;
+    info!("{}", "Done!".cyan().bold());
+
+    Ok(())
+}
+
+/// Compare `dest_dir` with `send_config.path` on Android, without transferring anything
+/// unless `repair` is set.
+///
+/// Files only on the host are reported, but never deleted, and don't fail the check.
+pub fn verify<S: Read + Write>(
+    mut stream: S,
+    send_config: SendConfig,
+    dest_dir: &Path,
+    token: Option<&AuthToken>,
+    repair: bool,
 ) -> anyhow::Result<()> {
-    macro_rules! check_ok {
-        () => {
-            if (stream.read_bincode::<Message>()? != Message::Ok) {
-                return Err(anyhow!("Android is not OK!"));
+    let hash = send_config.hash;
+    let index = open_session(&mut stream, send_config, token)?;
+
+    info!("{}", "Indexing the host copy...".cyan().bold());
+    let host_index = if dest_dir.exists() {
+        index_dir(dest_dir, true, &IndexFilter::default())?
+    } else {
+        Index::default()
+    };
+    let mut host_entries = host_index
+        .entries
+        .into_iter()
+        .filter(|x| !x.path.0.starts_with(manifest::MANIFEST_DIR))
+        .map(|x| (x.path.0.clone(), x))
+        .collect::<HashMap<_, _>>();
+
+    let mut missing = Vec::new();
+    let mut size_mismatched = Vec::new();
+    let mut same_size = Vec::new();
+    for entry in index.entries {
+        match host_entries.remove(&entry.path.0) {
+            None => missing.push(entry),
+            Some(x) if x.size != entry.size => size_mismatched.push(entry),
+            Some(_) => same_size.push(entry),
+        }
+    }
+    let mut extra = host_entries.into_keys().collect::<Vec<_>>();
+    extra.sort();
+
+    info!(
+        "{}",
+        format!("Hashing {} files ({})...", same_size.len(), hash)
+            .cyan()
+            .bold()
+    );
+    let digests = request_digests(
+        &mut stream,
+        same_size.len(),
+        Message::HashFiles(same_size.iter().map(|x| x.path.clone()).collect()),
+    )?;
+    let mut content_mismatched = Vec::new();
+    let mut unhashed = Vec::new();
+    let mut matched = 0_usize;
+    for (entry, digest) in same_size.into_iter().zip(digests) {
+        let digest = match digest {
+            Ok(x) => x,
+            Err(reason) => {
+                unhashed.push(Skipped {
+                    path: entry.path,
+                    reason,
+                });
+                continue;
             }
         };
+        match hash_file(dest_dir.join(&entry.path.0), hash) {
+            Ok(x) if x == digest => matched += 1,
+            Ok(_) => content_mismatched.push(entry),
+            Err(e) => {
+                warn!("Can't hash the host copy of {}: {}", entry.path, e);
+                content_mismatched.push(entry);
+            }
+        }
+    }
+
+    info!("{}", format!("Matched: {}", matched).cyan().bold());
+    report_entries("Missing on the host", &missing);
+    report_entries("Size mismatched", &size_mismatched);
+    report_entries("Content mismatched", &content_mismatched);
+    if !extra.is_empty() {
+        warn!("Only on the host: {}", extra.len());
+        for x in &extra {
+            warn!("  {}", x.display());
+        }
     }
+    report_skipped("Skipped while indexing", &index.skipped);
+    report_skipped("Couldn't hash on Android", &unhashed);
 
+    let mismatched = [missing, size_mismatched, content_mismatched].concat();
+    if !repair || mismatched.is_empty() {
+        stream.write_bincode(Message::Finish)?;
+        if !mismatched.is_empty() {
+            return Err(anyhow!(
+                "{} files on Android don't match the host copy",
+                mismatched.len()
+            ));
+        }
+        info!("{}", "Done!".cyan().bold());
+        return Ok(());
+    }
+
+    info!(
+        "{}",
+        format!("Repairing {} files...", mismatched.len())
+            .cyan()
+            .bold()
+    );
+    let send_list = mismatched.iter().map(SendItem::whole).collect::<Vec<_>>();
+    let not_received = transfer(&mut stream, &send_list, dest_dir, hash, &[])?;
+    stream.write_bincode(Message::Finish)?;
+    check_received(&not_received, mismatched.len())?;
+    info!("{}", "Done!".cyan().bold());
+
+    Ok(())
+}
+
+/// Handshake, then index `send_config.path` on Android.
+fn open_session<S: Read + Write>(
+    stream: &mut S,
+    send_config: SendConfig,
+    token: Option<&AuthToken>,
+) -> anyhow::Result<Index> {
     info!("{}", "Start sending...".cyan().bold());
     stream.write_all(MAGIC)?;
+    let hello = Hello::new();
+    stream.write_bincode(hello)?;
+    // a build predating the handshake hangs up instead of answering
+    let device_hello: Hello = stream.read_bincode().map_err(|e| {
+        anyhow!(
+            "Android didn't answer the handshake ({}). Its binary is probably from an \
+            incompatible version; reinstall it",
+            e
+        )
+    })?;
+    let capabilities = Hello::negotiate(hello, device_hello)?;
+    debug!("Capabilities: {:?}", capabilities);
+    if !capabilities.contains(send_config.hash.capability()) {
+        return Err(anyhow!(
+            "Android doesn't support hash: {}",
+            send_config.hash
+        ));
+    }
+    if let Some(token) = token {
+        respond(stream, token)?;
+        check_ok!(stream);
+    }
     stream.write_bincode(Message::StartIndexing(send_config))?;
-    check_ok!();
 
     info!("{}", "Indexing...".cyan().bold());
-    let entries = stream.read_bincode::<Vec<Entry>>()?;
+    check_ok!(stream);
+    let index = stream.read_bincode::<Index>()?;
     info!(
         "{}",
         format!(
             "Entries: {}, {}",
-            entries.len(),
-            ByteSize(entries.iter().map(|x| x.size).sum::<u64>()).to_string_as(true)
+            index.entries.len(),
+            ByteSize(index.entries.iter().map(|x| x.size).sum::<u64>()).to_string_as(true)
         )
         .cyan()
         .bold()
     );
-    check_ok!();
+    if index.filtered != 0 {
+        info!(
+            "Files excluded by filters (size, age, rules): {}",
+            index.filtered
+        );
+    }
+    if index.pruned_dirs != 0 {
+        info!("Directories excluded by rules: {}", index.pruned_dirs);
+    }
+    check_ok!(stream);
+    Ok(index)
+}
 
-    info!("{}", "Generating send list...".cyan().bold());
-    let send_list = generate_send_list(entries, dest_dir)?;
-    info!(
-        "{}",
-        format!(
-            "Send list: {}, {}",
-            send_list.len(),
-            ByteSize(send_list.iter().map(|x| x.size).sum::<u64>()).to_string_as(true)
+/// Send `request`, `HashFiles` or `HashRanges` of `count` files, and read the digests.
+fn request_digests<S: Read + Write>(
+    stream: &mut S,
+    count: usize,
+    request: Message,
+) -> anyhow::Result<Vec<Result<Vec<u8>, String>>> {
+    stream.write_bincode(request)?;
+    let Message::Digests(digests) = read_message(stream)? else {
+        return Err(anyhow!("Expected the digest list"));
+    };
+    if digests.len() != count {
+        return Err(anyhow!(
+            "Asked for {} digests, got {}",
+            count,
+            digests.len()
+        ));
+    }
+    Ok(digests)
+}
+
+/// Match the new files against `orphans`, host files gone from Android, and mark the
+/// matched ones [`SendDecision::FoundOnHost`].
+///
+/// With `confirm`, a match also needs the same digest on both sides.
+fn detect_renames<S: Read + Write>(
+    stream: &mut S,
+    decisions: &mut [(Entry, SendDecision)],
+    orphans: Vec<Entry>,
+    dest_dir: &Path,
+    hash: HashAlgorithm,
+    modify_window: Duration,
+    confirm: bool,
+) -> anyhow::Result<Vec<Rename>> {
+    let matcher = Matcher::new(orphans, modify_window);
+    let new_entries = decisions
+        .iter()
+        .filter(|(_, x)| *x == SendDecision::New)
+        .map(|(x, _)| x)
+        .filter(|x| !matcher.candidates(x).is_empty())
+        .collect::<Vec<_>>();
+
+    let renames = if confirm {
+        let digests = request_digests(
+            stream,
+            new_entries.len(),
+            Message::HashFiles(new_entries.iter().map(|x| x.path.clone()).collect()),
+        )?;
+        let android_digests = new_entries
+            .iter()
+            .zip(digests)
+            .filter_map(|(x, digest)| Some((x.path.0.as_path(), digest.ok()?)))
+            .collect::<HashMap<_, _>>();
+        let mut host_digests = HashMap::new();
+        matcher.plan(
+            &new_entries,
+            Some(|entry: &Entry, orphan: &Entry| {
+                let Some(digest) = android_digests.get(entry.path.0.as_path()) else {
+                    return false;
+                };
+                let host_digest = host_digests
+                    .entry(orphan.path.0.clone())
+                    .or_insert_with(|| hash_file(dest_dir.join(&orphan.path.0), hash).ok());
+                host_digest.as_ref() == Some(digest)
+            }),
         )
-        .cyan()
-        .bold()
-    );
-    stream.write_bincode(&send_list)?;
-    check_ok!();
+    } else {
+        matcher.plan(&new_entries, None::<fn(&Entry, &Entry) -> bool>)
+    };
+
+    let renamed = renames.iter().map(|x| &x.to).collect::<HashSet<_>>();
+    for (entry, decision) in decisions.iter_mut() {
+        if renamed.contains(&entry.path.0) {
+            *decision = SendDecision::FoundOnHost;
+        }
+    }
+    Ok(renames)
+}
+
+/// Compare the end of every host copy to append to with the same bytes on Android, and
+/// send the whole files where they differ.
+fn verify_appends<S: Read + Write>(
+    stream: &mut S,
+    decisions: &mut [(Entry, SendDecision)],
+    dest_dir: &Path,
+    hash: HashAlgorithm,
+) -> anyhow::Result<()> {
+    let ranges = decisions
+        .iter()
+        .filter_map(|(entry, decision)| match *decision {
+            SendDecision::Append { offset } => Some(HashRange {
+                path: entry.path.clone(),
+                start: offset.saturating_sub(APPEND_VERIFY_LENGTH),
+                end: offset,
+            }),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if ranges.is_empty() {
+        return Ok(());
+    }
+    let digests = request_digests(stream, ranges.len(), Message::HashRanges(ranges.clone()))?;
+
+    let mut mismatched = HashSet::new();
+    for (range, digest) in ranges.iter().zip(digests) {
+        let host_digest =
+            hash_file_range(dest_dir.join(&range.path.0), hash, range.start, range.end);
+        if digest.ok() != host_digest.ok() {
+            mismatched.insert(&range.path.0);
+        }
+    }
+    for (entry, decision) in decisions.iter_mut() {
+        if mismatched.contains(&entry.path.0) {
+            info!("Not a prefix, sending it whole: {}", entry.path);
+            *decision = SendDecision::Changed;
+        }
+    }
+    Ok(())
+}
+
+/// Receive the files of `send_list`, and report what happened to them.
+///
+/// `renamed` were moved on the host beforehand. Returns the files that never arrived.
+///
+/// What's in place is recorded even if the transfer fails halfway.
+fn transfer<S: Read + Write>(
+    stream: &mut S,
+    send_list: &[SendItem],
+    dest_dir: &Path,
+    hash: HashAlgorithm,
+    renamed: &[Rename],
+) -> anyhow::Result<Vec<UnixPath>> {
+    let config = mutex_lock!(CONFIG).clone().unwrap();
+    let options = ReceiveOptions {
+        hash,
+        perms: config.perms,
+        owner: config.owner,
+        chmod: config.chmod.clone(),
+        xattrs: config.send_config.xattrs.is_some(),
+        quiet: config.json,
+    };
+    let mut summary = ReceiveSummary::default();
+    let result = receive_send_list(stream, send_list, dest_dir, &options, &mut summary);
+
+    if !summary.changed.is_empty() {
+        warn!(
+            "Changed during transfer (will be sent again next time): {}",
+            summary.changed.len()
+        );
+        for x in &summary.changed {
+            warn!("  {}", x.display());
+        }
+    }
+    if let Some(path) = &config.digest_log {
+        write_digest_log(path, &summary.files)?;
+        info!("Digests ({}) appended to {}", hash, path.display());
+    }
+    if config.manifest {
+        manifest::update(
+            dest_dir,
+            &summary.files,
+            renamed,
+            hash,
+            &config.source_device,
+        )?;
+        info!(
+            "Manifest updated: {}",
+            dest_dir.join(manifest::MANIFEST_DIR).display()
+        );
+    }
+    if !summary.unapplied_xattrs.is_empty() {
+        let count = xattrs::update_sidecar(dest_dir, &summary.unapplied_xattrs)?;
+        if count != 0 {
+            warn!(
+                "Xattrs of {} files couldn't be set; kept in {}",
+                count,
+                dest_dir
+                    .join(manifest::MANIFEST_DIR)
+                    .join("xattrs")
+                    .display()
+            );
+        }
+    }
+    let send_skipped = result?;
+    report_skipped("Skipped while sending", &send_skipped);
+    let missing = send_list
+        .iter()
+        .filter(|x| !summary.received.contains(&x.path.0))
+        .map(|x| x.path.clone())
+        .collect::<Vec<_>>();
+    for x in &missing {
+        warn!("Not received: {}", x);
+    }
+    Ok(missing)
+}
+
+/// Have Android send `send_list`, and receive it into `summary`.
+///
+/// Returns the files Android skipped.
+fn receive_send_list<S: Read + Write>(
+    stream: &mut S,
+    send_list: &[SendItem],
+    dest_dir: &Path,
+    options: &ReceiveOptions,
+    summary: &mut ReceiveSummary,
+) -> anyhow::Result<Vec<Skipped>> {
+    stream.write_bincode(Message::SendList(send_list.to_vec()))?;
+    check_ok!(stream);
 
     info!("{}", "Receiving...".cyan().bold());
-    receive(&mut stream, dest_dir)?;
-    check_ok!();
-    info!("{}", "Done!".cyan().bold());
+    let result;
+    (*summary, result) = receive(&mut *stream, dest_dir, options);
+    result?;
+    let Message::Skipped(send_skipped) = read_message(stream)? else {
+        return Err(anyhow!("Expected the skipped file list"));
+    };
+    check_ok!(stream);
+    Ok(send_skipped)
+}
+
+fn check_received(missing: &[UnixPath], total: usize) -> anyhow::Result<()> {
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "{} of {} files in the send list were not received",
+            missing.len(),
+            total
+        ));
+    }
+    Ok(())
+}
 
+fn report_entries(title: &str, entries: &[Entry]) {
+    if entries.is_empty() {
+        return;
+    }
+    warn!("{}: {}", title, entries.len());
+    for x in entries {
+        warn!("  {}", x.path);
+    }
+}
+
+fn report_skipped(title: &str, skipped: &[Skipped]) {
+    if skipped.is_empty() {
+        return;
+    }
+    warn!("{}: {}", title, skipped.len());
+    for x in skipped {
+        warn!("  {}: {}", x.path, x.reason);
+    }
+}
+
+/// Append `<hex>  <path>` lines, as printed by `b3sum` and the like.
+///
+/// Paths are relative to the destination directory.
+fn write_digest_log(path: &Path, files: &[ReceivedFile]) -> anyhow::Result<()> {
+    let file = File::options().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    for x in files {
+        writeln!(writer, "{}  {}", hex::encode(&x.digest), x.path.display())?;
+    }
+    writer.flush()?;
     Ok(())
 }
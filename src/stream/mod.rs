@@ -7,6 +7,8 @@ use readwrite::ReadWrite;
 use crate::bincode_config;
 
 pub mod android;
+pub mod auth;
+pub mod crypto;
 pub mod host;
 pub mod protocol;
 
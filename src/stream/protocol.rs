@@ -1,6 +1,13 @@
 use bincode::{Decode, Encode};
+use std::fmt::{Display, Formatter};
+use std::io;
 use std::path::PathBuf;
 
+use crate::hash::HashAlgorithm;
+use crate::unix_path::UnixPath;
+use crate::xattrs::XattrFilter;
+use crate::{Entry, IndexFilter, PathContext, Skipped};
+
 /// # Protocol
 ///
 /// TODO
@@ -10,13 +17,182 @@ use std::path::PathBuf;
 pub enum Message {
     Ok = 1,
     StartIndexing(SendConfig),
+    /// Files Android left out of the send stream
+    Skipped(Vec<Skipped>),
+    /// Sent by Android in place of the expected reply before it gives up
+    Error {
+        kind: RemoteErrorKind,
+        message: String,
+        path: Option<UnixPath>,
+    },
+    /// Files for Android to send; answered with the send stream
+    SendList(Vec<SendItem>),
+    /// Files for Android to hash with the negotiated algorithm; answered with `Digests`
+    HashFiles(Vec<UnixPath>),
+    /// Like `HashFiles`, but only parts of the files
+    HashRanges(Vec<HashRange>),
+    /// In the order of `HashFiles` or `HashRanges`; `Err` holds why a file couldn't be hashed
+    Digests(Vec<Result<Vec<u8>, String>>),
+    /// The host is done with the session
+    Finish,
+}
+
+impl Message {
+    pub fn error(error: &anyhow::Error) -> Self {
+        let kind = match error.downcast_ref::<io::Error>().map(|e| e.kind()) {
+            Some(io::ErrorKind::NotFound) => RemoteErrorKind::NotFound,
+            Some(io::ErrorKind::PermissionDenied) => RemoteErrorKind::PermissionDenied,
+            Some(_) => RemoteErrorKind::Io,
+            None if error.is::<bincode::error::DecodeError>() => RemoteErrorKind::Protocol,
+            None => RemoteErrorKind::Other,
+        };
+        Message::Error {
+            kind,
+            message: error.root_cause().to_string(),
+            path: error
+                .downcast_ref::<PathContext>()
+                .map(|x| x.0.clone().into()),
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct SendItem {
+    pub path: UnixPath,
+    /// Only send the content from here; the host has the bytes before
+    pub offset: u64,
+}
+
+impl SendItem {
+    pub fn whole(entry: &Entry) -> Self {
+        Self {
+            path: entry.path.clone(),
+            offset: 0,
+        }
+    }
+}
+
+/// The bytes from `start` to `end` of a file
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct HashRange {
+    pub path: UnixPath,
+    pub start: u64,
+    pub end: u64,
 }
 
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RemoteErrorKind {
+    NotFound,
+    PermissionDenied,
+    Io,
+    Protocol,
+    Other,
+}
+
+/// An error Android reported through `Message::Error`
+#[derive(Debug)]
+pub struct RemoteError {
+    pub kind: RemoteErrorKind,
+    pub message: String,
+    pub path: Option<UnixPath>,
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Android error ({:?}): {}", self.kind, self.message)?;
+        if let Some(path) = &self.path {
+            write!(f, ": {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub struct SendConfig {
     pub path: PathBuf,
     /// Skip failures while indexing
     pub skip_failed: bool,
+    /// Digest of the file records; both sides must support it
+    pub hash: HashAlgorithm,
+    /// Send the xattrs of these namespaces
+    pub xattrs: Option<XattrFilter>,
+    /// Leave the files out of the index
+    pub filter: IndexFilter,
 }
 
 pub const MAGIC: &[u8; 11] = b"sync-stream";
+
+/// Bump this on every incompatible protocol change, including the framing below the
+/// [`Hello`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features, as a bit set
+///
+/// Bits are only defined for what can differ between two builds speaking the same
+/// [`PROTOCOL_VERSION`], which currently is the set of content hashes. Anything else
+/// changing the wire format bumps the version instead.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const BLAKE3: Capabilities = Capabilities(1 << 0);
+    pub const XXH3: Capabilities = Capabilities(1 << 1);
+    pub const SHA256: Capabilities = Capabilities(1 << 2);
+
+    /// Everything this build supports
+    pub fn supported() -> Self {
+        Self::BLAKE3.union(Self::XXH3).union(Self::SHA256)
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Sent by both sides right after `MAGIC`, before anything else
+///
+/// This isn't a [`Message`] and its layout must never change, so mismatched builds
+/// can always tell each other their versions.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+        }
+    }
+
+    /// Check the versions match, and return the capabilities both sides have.
+    pub fn negotiate(host: Hello, device: Hello) -> anyhow::Result<Capabilities> {
+        if host.version != device.version {
+            return Err(anyhow::anyhow!(
+                "Incompatible protocol: host v{} vs device v{}. Reinstall the Android binary",
+                host.version,
+                device.version
+            ));
+        }
+        Ok(host.capabilities.intersection(device.capabilities))
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -9,7 +9,7 @@ use std::os;
 /// `bincode` doesn't support (de)serializing non-UTF-8 `PathBuf`s
 use std::path::PathBuf;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UnixPath(pub PathBuf);
 
 impl Display for UnixPath {
@@ -0,0 +1,161 @@
+//! Extended attributes, `security.selinux` included
+//!
+//! Android collects them into the record headers, and the host applies them. The ones the
+//! host refuses are kept in `.adb-sync/xattrs` in the dump format of `getfattr`, so they can be
+//! restored with `setfattr --restore=.adb-sync/xattrs` from the destination directory.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use bincode::{Decode, Encode};
+use xattr::FileExt;
+
+use crate::manifest;
+use crate::manifest::MANIFEST_DIR;
+
+pub const SIDECAR_FILE: &str = "xattrs";
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Namespaces to keep, like `security` or `user`; empty keeps all of them
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, Default)]
+pub struct XattrFilter(pub Vec<String>);
+
+impl FromStr for XattrFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(String::from)
+                .collect(),
+        ))
+    }
+}
+
+impl XattrFilter {
+    fn keeps(&self, name: &str) -> bool {
+        self.0.is_empty()
+            || name
+                .split_once('.')
+                .is_some_and(|(namespace, _)| self.0.iter().any(|x| x == namespace))
+    }
+}
+
+/// Read the extended attributes of `path` passing `filter`, without following symlinks.
+///
+/// A filesystem without xattr support just has none.
+pub fn collect(path: &Path, filter: &XattrFilter) -> io::Result<Vec<Xattr>> {
+    let names = match xattr::list(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = Vec::new();
+    for name in names {
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if !filter.keeps(name) {
+            continue;
+        }
+        // `None` if it's gone meanwhile
+        if let Some(value) = xattr::get(path, name)? {
+            xattrs.push(Xattr {
+                name: name.into(),
+                value,
+            });
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Set `xattrs` on `file`, and return the ones the host refused.
+pub fn apply(file: &File, xattrs: &[Xattr]) -> Vec<Xattr> {
+    xattrs
+        .iter()
+        .filter(|x| file.set_xattr(&x.name, &x.value).is_err())
+        .cloned()
+        .collect()
+}
+
+/// Merge `unapplied` into the sidecar of `dest_dir`.
+///
+/// An empty list drops the entry of that file, as all its xattrs are in place now.
+/// Returns how many files the sidecar has.
+pub fn update_sidecar(
+    dest_dir: &Path,
+    unapplied: &BTreeMap<PathBuf, Vec<Xattr>>,
+) -> anyhow::Result<usize> {
+    let sidecar_path = dest_dir.join(MANIFEST_DIR).join(SIDECAR_FILE);
+    let mut sidecar = if sidecar_path.exists() {
+        parse_dump(&fs::read_to_string(&sidecar_path)?)?
+    } else {
+        BTreeMap::new()
+    };
+    for (path, xattrs) in unapplied {
+        if xattrs.is_empty() {
+            sidecar.remove(path);
+        } else {
+            sidecar.insert(path.clone(), xattrs.clone());
+        }
+    }
+
+    if sidecar.is_empty() {
+        if sidecar_path.exists() {
+            fs::remove_file(&sidecar_path)?;
+        }
+        return Ok(0);
+    }
+    fs::create_dir_all(dest_dir.join(MANIFEST_DIR))?;
+    manifest::write_replacing(&sidecar_path, |writer| {
+        for (path, xattrs) in &sidecar {
+            writeln!(writer, "# file: {}", path.display())?;
+            for x in xattrs {
+                writeln!(writer, "{}=0x{}", x.name, hex::encode(&x.value))?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    })?;
+    Ok(sidecar.len())
+}
+
+/// Parse a `getfattr --dump -e hex` output, as written by [`update_sidecar`].
+fn parse_dump(dump: &str) -> anyhow::Result<BTreeMap<PathBuf, Vec<Xattr>>> {
+    let mut map = BTreeMap::new();
+    let mut current: Option<(PathBuf, Vec<Xattr>)> = None;
+    for line in dump.lines().chain([""]) {
+        if let Some(path) = line.strip_prefix("# file: ") {
+            current = Some((path.into(), Vec::new()));
+        } else if line.is_empty() {
+            if let Some((path, xattrs)) = current.take() {
+                map.insert(path, xattrs);
+            }
+        } else {
+            let (name, value) = line
+                .split_once("=0x")
+                .ok_or_else(|| anyhow!("Invalid xattr line: {}", line))?;
+            let (_, xattrs) = current
+                .as_mut()
+                .ok_or_else(|| anyhow!("Xattr without a file: {}", line))?;
+            xattrs.push(Xattr {
+                name: name.into(),
+                value: hex::decode(value)?,
+            });
+        }
+    }
+    Ok(map)
+}